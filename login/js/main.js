@@ -32,8 +32,7 @@
         }
 
         if(check) {
-            var data = `{ "type": "login", "username": "${username.value}", "password": "${password.value}" }`;
-            ws.send(data);
+            submit('session', username.value, password.value);
         }
 
         return false;
@@ -52,8 +51,7 @@
 
         if(check) {
             if(confirm(`Are you sure you want to create user '${username.value}'?`)) {
-                var data = `{ "type": "new_user", "username": "${username.value}", "password": "${password.value}" }`;
-                ws.send(data);
+                submit('user', username.value, password.value);
             }
         }
 
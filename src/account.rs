@@ -0,0 +1,149 @@
+use crate::config::Config;
+use crate::protocol::{ClientMsg, ServerMsg, PROTOCOL_VERSION};
+use crate::ratelimit::Class;
+use crate::session::{self, SESSION_TTL};
+use crate::validate::{self, Invalid};
+use crate::{authenticate, too_many_attempts, App};
+use corundum::default::*;
+use hex::ToHex;
+use log::{error, warn};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use warp::http::{header, StatusCode};
+use warp::Reply;
+
+type P = BuddyAlloc;
+
+/// The cookie that holds the session of the browser
+pub const COOKIE: &str = "wb_session";
+
+/// The login page, in the login directory
+const PAGE: &str = "index.html";
+
+/// What the login page posts to sign in or up
+#[derive(Deserialize)]
+pub struct Form {
+    username: String,
+    password: String,
+    /// The protocol version the page speaks
+    version: u32,
+}
+
+/// Renders the login page
+pub fn page(config: &Config) -> Box<dyn Reply> {
+    match fs::read_to_string(config.assets.login.join(PAGE)) {
+        Ok(page) => Box::new(warp::reply::html(
+            page.replace("{{protocol_version}}", &PROTOCOL_VERSION.to_string()),
+        )),
+        Err(e) => {
+            error!("cannot read the login page: {}", e);
+            Box::new(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Signs a user in, or registers them if `create` is set, and hands the
+/// session to the browser as a cookie. The answer is the same message the
+/// WebSocket would send.
+pub async fn sign_in(
+    form: Form,
+    create: bool,
+    remote: Option<SocketAddr>,
+    app: App,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let App { root, clients, client_lockouts, config, .. } = app;
+    if form.version != PROTOCOL_VERSION {
+        let msg = ServerMsg::UnsupportedVersion { version: PROTOCOL_VERSION };
+        return Ok(answer(StatusCode::BAD_REQUEST, &msg));
+    }
+    let cmd = if create {
+        ClientMsg::NewUser { username: form.username.clone(), password: form.password.clone() }
+    } else {
+        ClientMsg::Login { username: form.username.clone(), password: form.password.clone() }
+    };
+    if let Err(e) = validate::check(&cmd) {
+        return Ok(reject(e));
+    }
+    // Clients are told apart by address, never by the name they try, so that
+    // nobody can use up the limits of someone else's account
+    let client = remote.map_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED), |addr| addr.ip());
+    if let Some(wait) = client_lockouts.blocked(client) {
+        return Ok(reject(too_many_attempts(wait)));
+    }
+    if !clients.allow(key(client), Class::Auth) {
+        return Ok(reject(Invalid::new("rate_limited", "too many auth commands, slow down")));
+    }
+
+    let color = config.color(rand::random());
+    match authenticate(&root, &form.username, &form.password, create, color).await {
+        Ok((msg, _, token)) => {
+            client_lockouts.reset(client);
+            let cookie = cookie(config, &token.encode_hex::<String>(), SESSION_TTL);
+            Ok(Box::new(warp::reply::with_header(
+                answer(StatusCode::OK, &msg),
+                header::SET_COOKIE,
+                cookie,
+            )))
+        }
+        Err(e) => {
+            if e.code == "wrong" || e.code == "not_exists" {
+                client_lockouts.fail(client);
+            }
+            Ok(reject(e))
+        }
+    }
+}
+
+/// The key of a client address among the rate limits of the clients
+fn key(client: IpAddr) -> [u8; 16] {
+    match client {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// Closes the session of the browser, forgets the cookie, and goes back to
+/// the login page
+pub async fn sign_out(cookie: Option<String>, app: App) -> Result<Box<dyn Reply>, Infallible> {
+    let App { root, config, .. } = app;
+    if let Some(token) = cookie.as_deref().and_then(session::parse_token) {
+        if let Err(e) = P::transaction(|j| {
+            if let Some(root) = root.promote(j) {
+                root.lock(j).sessions.close(&token, j);
+            }
+        }) {
+            warn!("cannot close the session: {}", e);
+        }
+    }
+    let back = warp::reply::with_header(StatusCode::SEE_OTHER, header::LOCATION, "/login/");
+    Ok(Box::new(warp::reply::with_header(
+        back,
+        header::SET_COOKIE,
+        self::cookie(config, "", 0),
+    )))
+}
+
+fn cookie(config: &Config, value: &str, max_age: u64) -> String {
+    let secure = if config.tls.is_some() { "; Secure" } else { "" };
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{}",
+        COOKIE, value, max_age, secure
+    )
+}
+
+fn answer(status: StatusCode, msg: &ServerMsg) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(msg), status))
+}
+
+fn reject(e: Invalid) -> Box<dyn Reply> {
+    let status = match e.code {
+        "wrong" | "not_exists" => StatusCode::UNAUTHORIZED,
+        "user_exists" => StatusCode::CONFLICT,
+        "too_many_attempts" | "rate_limited" => StatusCode::TOO_MANY_REQUESTS,
+        "internal" => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    answer(status, &e.login_reply())
+}
@@ -0,0 +1,345 @@
+use crate::board::line_id;
+use crate::protocol::{ClientMsg, Point, ServerMsg};
+use crate::ratelimit::Class;
+use crate::role::Role;
+use crate::session;
+use crate::svg;
+use crate::validate::{self, Invalid};
+use crate::{
+    allowed, apply, bad_session, draw_line, internal, no_board, not_signed_in, App, Credentials,
+    Limits,
+};
+use corundum::default::*;
+use md5::compute;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use warp::http::{header, StatusCode};
+use warp::Reply;
+
+type P = BuddyAlloc;
+
+/// A line to draw, as the `draw` command gives it
+#[derive(Deserialize)]
+pub struct NewLine {
+    data: Vec<Point>,
+}
+
+/// Which lines to export as SVG; times are in seconds since the Unix epoch
+#[derive(Deserialize, Default)]
+pub struct SvgQuery {
+    author: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+/// A line of a history, whether it is currently visible or undone
+#[derive(Serialize)]
+struct Entry {
+    id: String,
+    color: u32,
+    data: Vec<Point>,
+    visible: bool,
+}
+
+/// The lines a user drew on a board, in drawing order
+#[derive(Serialize)]
+struct BoardHistory {
+    board: String,
+    lines: Vec<Entry>,
+}
+
+/// `GET /api/boards/<board>/lines`: the visible lines of a board, in drawing
+/// order, as the `redraw` message
+pub async fn lines(
+    board: String,
+    auth: Option<String>,
+    cookie: Option<String>,
+    app: App,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let App { root, registry, admins, limits, .. } = app;
+    let res = async {
+        let creds = credentials(auth, cookie, &limits, Class::Read)?;
+        let room = registry.read().await.get(&board).ok_or_else(no_board)?;
+        allowed(&root, &admins, room.id, creds, Role::Viewer)?;
+        let msg = room.snapshot.write().await.redraw();
+        Ok::<_, Invalid>(msg)
+    };
+    Ok(respond(StatusCode::OK, res.await))
+}
+
+/// `GET /api/boards/<board>/svg`: the visible lines of a board as an SVG
+/// document, optionally only those of `author`, or drawn from `since` and
+/// before `until`
+pub async fn export_svg(
+    board: String,
+    query: SvgQuery,
+    auth: Option<String>,
+    cookie: Option<String>,
+    app: App,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let App { root, registry, admins, limits, .. } = app;
+    let res = async {
+        let creds = credentials(auth, cookie, &limits, Class::Read)?;
+        let author = match &query.author {
+            Some(name) => {
+                validate::name(name)?;
+                Some(*compute(name))
+            }
+            None => None,
+        };
+        let filter = svg::Filter {
+            author,
+            since: query.since.map(time).transpose()?,
+            until: query.until.map(time).transpose()?,
+        };
+        let room = registry.read().await.get(&board).ok_or_else(no_board)?;
+        allowed(&root, &admins, room.id, creds, Role::Viewer)?;
+        let id = room.id;
+        match P::transaction(|j| {
+            let root = root.promote(j)?;
+            let root = root.lock(j);
+            let doc = root.boards.get_ref(id).map(|b| svg::export(&b.histories, &filter, j));
+            doc
+        }) {
+            Ok(Some(doc)) => Ok(doc),
+            Ok(None) => Err(no_board()),
+            Err(e) => Err(internal(e)),
+        }
+    };
+    match res.await {
+        Ok(doc) => Ok(Box::new(warp::reply::with_header(
+            doc,
+            header::CONTENT_TYPE,
+            "image/svg+xml",
+        ))),
+        Err(e) => Ok(failure(e)),
+    }
+}
+
+/// `POST /api/boards/<board>/lines`: draws a line as the signed-in user, and
+/// answers with the `line_added` event sent to the clients on the board
+pub async fn add_line(
+    board: String,
+    line: NewLine,
+    auth: Option<String>,
+    cookie: Option<String>,
+    app: App,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let App { root, registry, admins, limits, .. } = app;
+    let res = async {
+        let creds = credentials(auth, cookie, &limits, Class::Draw)?;
+        let cmd = ClientMsg::Draw { data: line.data };
+        validate::check(&cmd)?;
+        let points: Vec<(i32, i32)> = match &cmd {
+            ClientMsg::Draw { data } => data.iter().map(|p| (p.x, p.y)).collect(),
+            _ => vec![],
+        };
+        if points.is_empty() {
+            return Err(Invalid::new("empty_line", "a line needs at least one point"));
+        }
+        let room = registry.read().await.get(&board).ok_or_else(no_board)?;
+        let user = allowed(&root, &admins, room.id, creds, Role::Editor)?;
+        let events = apply(&room, &root, &admins, creds, 0, |j, c, h| {
+            draw_line(j, h, user, &points, c)
+        })
+        .await?;
+        events
+            .into_iter()
+            .next()
+            .ok_or_else(|| internal("the line was not added"))
+    };
+    Ok(respond(StatusCode::CREATED, res.await))
+}
+
+/// `GET /api/users/<name>/history`: every line the user drew, undone ones
+/// included, by board. Only the user and the admins may read it.
+pub async fn history(
+    name: String,
+    auth: Option<String>,
+    cookie: Option<String>,
+    app: App,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let App { root, admins, limits, .. } = app;
+    let res = (|| {
+        validate::name(&name)?;
+        let creds = credentials(auth, cookie, &limits, Class::Read)?;
+        let token = creds.session.ok_or_else(not_signed_in)?;
+        let target = *compute(&name);
+        let boards = match P::transaction(|j| {
+            let root = root.promote(j).ok_or_else(bad_session)?;
+            let root = root.lock(j);
+            let user = root.sessions.check(&token).ok_or_else(bad_session)?;
+            if user != target && !admins.contains(&user) {
+                return Err(Invalid::new(
+                    "forbidden",
+                    "only the user and the admins can read a history",
+                ));
+            }
+            if root.data.get_ref(target).is_none() {
+                return Err(Invalid::new("no_user", "the user does not exist"));
+            }
+            let mut boards = vec![];
+            root.boards.foreach(|_, b| {
+                if let Some(history) = b.histories.get_ref(target) {
+                    let mut lines = vec![];
+                    history.foreach(j, |line, visible| {
+                        lines.push(Entry {
+                            id: line_id(&(line.timestamp(), target)),
+                            color: line.color(),
+                            data: line.points().into_iter().map(|(x, y)| Point { x, y }).collect(),
+                            visible,
+                        })
+                    });
+                    boards.push(BoardHistory {
+                        board: b.name.as_str().to_string(),
+                        lines,
+                    });
+                }
+            });
+            boards.sort_by(|a, b| a.board.cmp(&b.board));
+            Ok(boards)
+        }) {
+            Ok(res) => res?,
+            Err(e) => return Err(internal(e)),
+        };
+        serde_json::to_string(&boards).map_err(internal)
+    })();
+    Ok(respond(StatusCode::OK, res))
+}
+
+/// The session of a request, given as `Authorization: Bearer <token>` or as
+/// the cookie of the login page. Requests are rate limited by session.
+fn credentials(
+    auth: Option<String>,
+    cookie: Option<String>,
+    limits: &Limits,
+    class: Class,
+) -> Result<Credentials, Invalid> {
+    let token = match (auth, cookie) {
+        (Some(auth), _) => match auth.strip_prefix("Bearer ") {
+            Some(token) => token.trim().to_string(),
+            None => {
+                return Err(Invalid::new(
+                    "bad_session",
+                    "expected an `Authorization: Bearer <session>` header",
+                ))
+            }
+        },
+        (None, Some(cookie)) => cookie,
+        (None, None) => return Err(not_signed_in()),
+    };
+    let session = session::parse_token(&token).ok_or_else(bad_session)?;
+    if !limits.allow(session, class) {
+        return Err(Invalid::new(
+            "rate_limited",
+            format!("too many {} requests, slow down", class.name()),
+        ));
+    }
+    Ok(Credentials {
+        user: [0; 16],
+        session: Some(session),
+        invite: None,
+    })
+}
+
+/// A time given in seconds since the Unix epoch
+fn time(secs: u64) -> Result<SystemTime, Invalid> {
+    UNIX_EPOCH
+        .checked_add(Duration::from_secs(secs))
+        .ok_or_else(|| Invalid::new("bad_request", format!("{} is too far in the future", secs)))
+}
+
+/// Sends `body` as JSON with `status`, or the error as an `error` message
+fn respond(status: StatusCode, res: Result<String, Invalid>) -> Box<dyn Reply> {
+    match res {
+        Ok(body) => json(status, body),
+        Err(e) => failure(e),
+    }
+}
+
+/// Sends the error as an `error` message, with the status matching its code
+fn failure(e: Invalid) -> Box<dyn Reply> {
+    let status = error_status(e.code);
+    json(status, ServerMsg::Error { code: e.code, message: e.message }.to_string())
+}
+
+fn error_status(code: &str) -> StatusCode {
+    match code {
+        "not_signed_in" | "bad_session" => StatusCode::UNAUTHORIZED,
+        "forbidden" => StatusCode::FORBIDDEN,
+        "no_board" | "no_user" => StatusCode::NOT_FOUND,
+        "rate_limited" => StatusCode::TOO_MANY_REQUESTS,
+        "internal" => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+fn json(status: StatusCode, body: String) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(
+        warp::reply::with_header(body, header::CONTENT_TYPE, "application/json"),
+        status,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "000102030405060708090a0b0c0d0e0f";
+
+    fn status(reply: Box<dyn Reply>) -> StatusCode {
+        reply.into_response().status()
+    }
+
+    #[test]
+    fn errors_get_the_status_of_their_code() {
+        assert_eq!(status(failure(internal("disk on fire"))), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(status(failure(no_board())), StatusCode::NOT_FOUND);
+        assert_eq!(status(failure(not_signed_in())), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(failure(bad_session())), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(failure(Invalid::new("too_long", ""))), StatusCode::BAD_REQUEST);
+        // The status given only applies to a success
+        assert_eq!(status(respond(StatusCode::CREATED, Ok("{}".to_string()))), StatusCode::CREATED);
+        assert_eq!(status(respond(StatusCode::CREATED, Err(no_board()))), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn credentials_come_from_the_header_then_the_cookie() {
+        let limits = Limits::default();
+        let token = session::parse_token(TOKEN);
+        let bearer = Some(format!("Bearer {}", TOKEN));
+        let creds = credentials(bearer, Some("ff".repeat(16)), &limits, Class::Read).unwrap();
+        assert_eq!(creds.session, token);
+        let creds = credentials(None, Some(TOKEN.to_string()), &limits, Class::Read).unwrap();
+        assert_eq!(creds.session, token);
+
+        let code = |auth: Option<&str>, cookie: Option<&str>| {
+            let (auth, cookie) = (auth.map(String::from), cookie.map(String::from));
+            credentials(auth, cookie, &limits, Class::Read).err().map(|e| e.code)
+        };
+        assert_eq!(code(None, None), Some("not_signed_in"));
+        assert_eq!(code(Some(TOKEN), None), Some("bad_session"));
+        assert_eq!(code(Some("Bearer nope"), None), Some("bad_session"));
+        assert_eq!(code(None, Some("nope")), Some("bad_session"));
+    }
+
+    #[test]
+    fn requests_are_rate_limited_by_session() {
+        let limits = Limits::default();
+        let allowed = (0..1000)
+            .take_while(|_| credentials(None, Some(TOKEN.to_string()), &limits, Class::Read).is_ok())
+            .count();
+        assert!(allowed < 1000);
+        let e = credentials(None, Some(TOKEN.to_string()), &limits, Class::Read).err();
+        assert_eq!(e.map(|e| e.code), Some("rate_limited"));
+        // Other sessions have their own limits
+        assert!(credentials(None, Some("ff".repeat(16)), &limits, Class::Read).is_ok());
+    }
+
+    #[test]
+    fn times_are_seconds_since_the_epoch() {
+        assert_eq!(time(60).unwrap(), UNIX_EPOCH + Duration::from_secs(60));
+        assert_eq!(time(u64::MAX).unwrap_err().code, "bad_request");
+    }
+}
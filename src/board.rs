@@ -0,0 +1,214 @@
+use crate::protocol::ServerMsg;
+use hex::ToHex;
+use log::error;
+use serde_json::value::{to_raw_value, RawValue};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A line is identified by its creation time and its author. The timestamp
+/// comes first, so iterating over the lines yields the global drawing order.
+pub type LineId = (SystemTime, [u8; 16]);
+
+/// The effect of a single command on the set of visible lines
+pub enum Change {
+    None,
+    Added(LineId, Value),
+    Hidden(LineId),
+    Restored(LineId, Value),
+    Cleared([u8; 16]),
+}
+
+/// The id of a line as seen by the clients. The timestamp is zero-padded so
+/// that sorting the ids as strings gives the drawing order.
+pub fn line_id(id: &LineId) -> String {
+    let nanos = id
+        .0
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("{:020}-{}", nanos, id.1.encode_hex::<String>())
+}
+
+/// A volatile, materialized view of the visible lines of the board.
+///
+/// It is built once from the pool at startup and then kept up to date with
+/// the changes made by `draw`, `undo`, `redo` and `clear`, so that a redraw
+/// does not need to walk the histories. Every line is serialized only once,
+/// and the whole `redraw` message is cached until the next change.
+#[derive(Default)]
+pub struct Snapshot {
+    version: u64,
+    lines: BTreeMap<LineId, Box<RawValue>>,
+    redraw: Option<String>,
+}
+
+impl Snapshot {
+    pub fn new(version: u64) -> Self {
+        Self {
+            version,
+            ..Default::default()
+        }
+    }
+
+    pub fn insert(&mut self, id: LineId, mut line: Value) -> Option<Box<RawValue>> {
+        line["id"] = json!(line_id(&id));
+        match to_raw_value(&line) {
+            Ok(line) => {
+                self.lines.insert(id, line.clone());
+                self.redraw = None;
+                Some(line)
+            }
+            Err(e) => {
+                error!("cannot serialize a line: {}", e);
+                None
+            }
+        }
+    }
+
+    fn bump(&mut self) -> u64 {
+        self.version += 1;
+        self.redraw = None;
+        self.version
+    }
+
+    /// Applies a change and returns the serialized events to broadcast.
+    ///
+    /// Every event carries the board version it produced; a client that sees
+    /// a version other than the one following its own has missed an event
+    /// and asks for a `redraw`.
+    pub fn apply(&mut self, change: Change) -> Vec<String> {
+        let events = match change {
+            Change::None => vec![],
+            Change::Added(id, line) => match self.insert(id, line) {
+                Some(line) => vec![ServerMsg::LineAdded { version: self.bump(), line }],
+                None => vec![],
+            },
+            Change::Restored(id, line) => match self.insert(id, line) {
+                Some(line) => vec![ServerMsg::LineRestored { version: self.bump(), line }],
+                None => vec![],
+            },
+            Change::Hidden(id) => {
+                if self.lines.remove(&id).is_some() {
+                    vec![ServerMsg::LineHidden { version: self.bump(), id: line_id(&id) }]
+                } else {
+                    vec![]
+                }
+            }
+            // A single event however many lines go, so that clearing a
+            // crowded board does not flood the clients
+            Change::Cleared(user) => {
+                let before = self.lines.len();
+                self.lines.retain(|(_, author), _| *author != user);
+                if self.lines.len() < before {
+                    let author = user.encode_hex::<String>();
+                    vec![ServerMsg::LinesCleared { version: self.bump(), author }]
+                } else {
+                    vec![]
+                }
+            }
+        };
+        events.iter().map(|e| e.to_string()).collect()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the serialized `redraw` message containing all visible lines
+    pub fn redraw(&mut self) -> String {
+        if let Some(msg) = &self.redraw {
+            return msg.clone();
+        }
+        let msg = ServerMsg::Redraw {
+            version: self.version,
+            data: self.lines.values().map(|line| line.as_ref()).collect(),
+        }
+        .to_string();
+        self.redraw = Some(msg.clone());
+        msg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn id(secs: u64, author: u8) -> LineId {
+        (UNIX_EPOCH + Duration::from_secs(secs), [author; 16])
+    }
+
+    fn line(x: i32) -> Value {
+        json!({ "color": 0, "data": [{ "x": x, "y": 0 }] })
+    }
+
+    fn parse(events: Vec<String>) -> Vec<Value> {
+        events.iter().map(|e| serde_json::from_str(e).unwrap()).collect()
+    }
+
+    #[test]
+    fn changes_bump_the_version_once_per_event() {
+        let mut snapshot = Snapshot::new(7);
+        let added = parse(snapshot.apply(Change::Added(id(1, 1), line(1))));
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0]["type"], "line_added");
+        assert_eq!(added[0]["version"], 8);
+        assert_eq!(added[0]["line"]["id"], line_id(&id(1, 1)));
+        assert_eq!(added[0]["line"]["data"][0]["x"], 1);
+
+        let hidden = parse(snapshot.apply(Change::Hidden(id(1, 1))));
+        assert_eq!(hidden[0]["type"], "line_hidden");
+        assert_eq!(hidden[0]["version"], 9);
+
+        let restored = parse(snapshot.apply(Change::Restored(id(1, 1), line(1))));
+        assert_eq!(restored[0]["type"], "line_restored");
+        assert_eq!(restored[0]["version"], 10);
+        assert_eq!(snapshot.version(), 10);
+    }
+
+    #[test]
+    fn changes_without_effect_keep_the_version() {
+        let mut snapshot = Snapshot::new(0);
+        assert!(snapshot.apply(Change::None).is_empty());
+        assert!(snapshot.apply(Change::Hidden(id(1, 1))).is_empty());
+        assert!(snapshot.apply(Change::Cleared([1; 16])).is_empty());
+        assert_eq!(snapshot.version(), 0);
+    }
+
+    #[test]
+    fn clearing_hides_only_the_lines_of_the_user() {
+        let mut snapshot = Snapshot::new(0);
+        snapshot.apply(Change::Added(id(1, 1), line(1)));
+        snapshot.apply(Change::Added(id(2, 2), line(2)));
+        snapshot.apply(Change::Added(id(3, 1), line(3)));
+        let cleared = parse(snapshot.apply(Change::Cleared([1; 16])));
+        assert_eq!(cleared.len(), 1);
+        assert_eq!(cleared[0]["type"], "lines_cleared");
+        assert_eq!(cleared[0]["author"], "01".repeat(16));
+        assert_eq!(cleared[0]["version"], 4);
+        // The author is the end of the ids of their lines
+        assert!(line_id(&id(1, 1)).ends_with(&format!("-{}", "01".repeat(16))));
+
+        let redraw: Value = serde_json::from_str(&snapshot.redraw()).unwrap();
+        assert_eq!(redraw["version"], 4);
+        assert_eq!(redraw["data"].as_array().unwrap().len(), 1);
+        assert_eq!(redraw["data"][0]["id"], line_id(&id(2, 2)));
+    }
+
+    #[test]
+    fn redraw_lists_the_lines_in_drawing_order() {
+        let mut snapshot = Snapshot::new(0);
+        snapshot.apply(Change::Added(id(2, 1), line(2)));
+        snapshot.apply(Change::Added(id(1, 2), line(1)));
+        let first = snapshot.redraw();
+        assert_eq!(snapshot.redraw(), first);
+        let redraw: Value = serde_json::from_str(&first).unwrap();
+        assert_eq!(redraw["data"][0]["data"][0]["x"], 1);
+        assert_eq!(redraw["data"][1]["data"][0]["x"], 2);
+
+        snapshot.apply(Change::Hidden(id(1, 2)));
+        let redraw: Value = serde_json::from_str(&snapshot.redraw()).unwrap();
+        assert_eq!(redraw["data"].as_array().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::protocol::ChatEntry;
+use crate::session::now;
+use corundum::default::*;
+use hex::ToHex;
+
+type P = BuddyAlloc;
+
+/// The number of messages sent at once, on connect or when asked for older
+/// ones
+pub const CHAT_PAGE: usize = 50;
+
+struct Message {
+    author: [u8; 16],
+    /// The name of the author when they wrote it
+    name: PString,
+    /// Seconds since the Unix epoch
+    time: u64,
+    text: PString,
+}
+
+/// The chat of a board. Messages are numbered in the order they were posted,
+/// starting from 0, and are kept for as long as the board exists.
+pub struct Chat {
+    messages: PVec<Message>,
+}
+
+impl RootObj<P> for Chat {
+    fn init(_j: &Journal) -> Self {
+        Chat {
+            messages: PVec::new(),
+        }
+    }
+}
+
+impl Chat {
+    pub fn post(&mut self, author: [u8; 16], name: &str, text: &str, j: &Journal) -> ChatEntry {
+        self.messages.push(
+            Message {
+                author,
+                name: name.to_pstring(j),
+                time: now(),
+                text: text.to_pstring(j),
+            },
+            j,
+        );
+        self.entry(self.messages.len() - 1)
+    }
+
+    fn entry(&self, id: usize) -> ChatEntry {
+        let m = &self.messages.as_slice()[id];
+        ChatEntry {
+            id: id as u64,
+            author: m.author.encode_hex::<String>(),
+            name: m.name.as_str().to_string(),
+            time: m.time,
+            text: m.text.as_str().to_string(),
+        }
+    }
+
+    /// Returns the last `CHAT_PAGE` messages before `before`, or the last ones
+    /// overall, oldest first, and whether there are older ones still
+    pub fn page(&self, before: Option<u64>) -> (Vec<ChatEntry>, bool) {
+        let len = self.messages.len();
+        let end = before.map_or(len, |b| (b as usize).min(len));
+        let start = end.saturating_sub(CHAT_PAGE);
+        ((start..end).map(|id| self.entry(id)).collect(), start > 0)
+    }
+}
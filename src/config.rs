@@ -0,0 +1,640 @@
+use crate::tls;
+use crate::validate;
+use corundum::default::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fmt, fs};
+
+/// Where the configuration is read from, unless `--config` or `WB_CONFIG`
+/// tell otherwise
+pub const DEFAULT_PATH: &str = "server.json";
+
+/// The settings that can be overridden from the environment, as `WB_HOST`
+/// and so on, or from the command line, as `--host`
+const OVERRIDES: [&str; 9] = [
+    "host",
+    "port",
+    "bind",
+    "log-level",
+    "pool-path",
+    "pool-size",
+    "index",
+    "tls-cert",
+    "tls-key",
+];
+
+/// The pool sizes Corundum can create
+const POOL_SIZES: [(&str, u32); 7] = [
+    ("1GB", O_1GB),
+    ("2GB", O_2GB),
+    ("4GB", O_4GB),
+    ("8GB", O_8GB),
+    ("16GB", O_16GB),
+    ("32GB", O_32GB),
+    ("64GB", O_64GB),
+];
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// URL prefixes that asset directories cannot take
+const RESERVED_PATHS: [&str; 4] = ["wb", "login", "api", "metrics"];
+
+/// Everything wrong with the configuration, one problem per line
+#[derive(Debug)]
+pub struct Error(Vec<String>);
+
+impl Error {
+    fn new<S: Into<String>>(problem: S) -> Self {
+        Error(vec![problem.into()])
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// A color given as `#rrggbb`
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(try_from = "String")]
+pub struct Color(pub u32);
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        validate::color(&s)
+            .map(Color)
+            .map_err(|_| format!("invalid color {:?}, expected #rrggbb", s))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The address of the server, used when `bind` is not given
+    pub host: String,
+    pub port: u16,
+    /// The addresses to listen on, such as `0.0.0.0:3035` or `[::]:3035`;
+    /// `port` is used when they have none. Defaults to `host`.
+    #[serde(default)]
+    pub bind: Vec<String>,
+    /// The users allowed to run admin commands, such as `unlock`
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// Filters in the `RUST_LOG` syntax, such as `info` or `warp=debug`
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    #[serde(default)]
+    pub pool: Pool,
+    #[serde(default)]
+    pub assets: Assets,
+    /// The colors handed out to new users and guests, in turn
+    #[serde(default = "default_palette")]
+    pub palette: Vec<Color>,
+    #[serde(default)]
+    pub limits: Limits,
+    /// Serves `https://` and `wss://` instead, when given
+    #[serde(default)]
+    pub tls: Option<Tls>,
+    /// The resolved `bind` addresses
+    #[serde(skip)]
+    pub addrs: Vec<SocketAddr>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pool {
+    pub path: String,
+    /// One of `1GB`, `2GB`, ... `64GB`; only used when creating the pool
+    pub size: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Assets {
+    /// The page served at `/`
+    pub index: PathBuf,
+    /// The login page and its assets, served at `/login/`
+    pub login: PathBuf,
+    /// Directories served as they are, by URL prefix
+    pub dirs: BTreeMap<String, PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    /// The certificate chain, in PEM
+    pub cert: PathBuf,
+    /// The private key, in PEM
+    pub key: PathBuf,
+    /// Seconds between two checks for a renewed certificate
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// How many times a second live strokes are sent out
+    pub tick_rate: u32,
+    /// Seconds between two pings to every client
+    pub ping_interval: u64,
+    /// Seconds without hearing from a client before it is disconnected
+    pub ping_timeout: u64,
+    /// Seconds without a command before a connection is signed out
+    pub idle_timeout: u64,
+    /// The largest message accepted from a client, in bytes
+    pub max_message_len: usize,
+}
+
+fn default_log_level() -> String {
+    "warn,wss=info".to_string()
+}
+
+fn default_reload_interval() -> u64 {
+    60
+}
+
+fn default_palette() -> Vec<Color> {
+    vec![
+        Color(0x000000),
+        Color(0xff0000),
+        Color(0x00ff00),
+        Color(0x0000ff),
+        Color(0x808000),
+        Color(0x800080),
+        Color(0x008080),
+        Color(0x404040),
+    ]
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Pool {
+            path: "users.pool".to_string(),
+            size: "2GB".to_string(),
+        }
+    }
+}
+
+impl Default for Assets {
+    fn default() -> Self {
+        Assets {
+            index: PathBuf::from("wb.html"),
+            login: PathBuf::from("login"),
+            dirs: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            tick_rate: 30,
+            ping_interval: 20,
+            ping_timeout: 60,
+            idle_timeout: 30 * 60,
+            max_message_len: validate::MAX_MESSAGE_LEN,
+        }
+    }
+}
+
+impl Pool {
+    /// The flags to open the pool with
+    pub fn flags(&self) -> u32 {
+        let size = POOL_SIZES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&self.size))
+            .map_or(O_2GB, |(_, flag)| *flag);
+        O_CFNE | size
+    }
+}
+
+impl Limits {
+    pub fn tick(&self) -> Duration {
+        Duration::from_secs(1) / self.tick_rate
+    }
+
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval)
+    }
+
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_secs(self.ping_timeout)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+}
+
+impl Config {
+    /// Reads the configuration file, applies the overrides from the
+    /// environment and then from the command line, and validates the result
+    pub fn load() -> Result<Config, Error> {
+        Config::load_from(env::args().skip(1), |var| env::var(var).ok())
+    }
+
+    /// Loads the configuration with the given command line arguments, less
+    /// the program, and environment
+    fn load_from<I, E>(args: I, var: E) -> Result<Config, Error>
+    where
+        I: Iterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let flags = parse_args(args)?;
+        let path = flags
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| var("WB_CONFIG"))
+            .unwrap_or_else(|| DEFAULT_PATH.to_string());
+
+        let text = fs::read_to_string(&path)
+            .map_err(|e| Error::new(format!("cannot read {}: {}", path, e)))?;
+        let mut config: Config =
+            serde_json::from_str(&text).map_err(|e| Error::new(format!("{}: {}", path, e)))?;
+
+        let mut problems = vec![];
+        for key in OVERRIDES.iter() {
+            let name = format!("WB_{}", key.to_uppercase().replace('-', "_"));
+            if let Some(value) = var(&name) {
+                if let Err(e) = config.set(key, &value) {
+                    problems.push(format!("{}: {}", name, e));
+                }
+            }
+        }
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            if let Err(e) = config.set(key, value) {
+                problems.push(format!("--{}: {}", key, e));
+            }
+        }
+        if !problems.is_empty() {
+            return Err(Error(problems));
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Overrides a single setting
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "host" => self.host = value.to_string(),
+            "port" => {
+                self.port = value
+                    .parse()
+                    .map_err(|_| format!("invalid port {:?}", value))?
+            }
+            "bind" => {
+                self.bind = value
+                    .split(',')
+                    .map(|addr| addr.trim().to_string())
+                    .filter(|addr| !addr.is_empty())
+                    .collect()
+            }
+            "log-level" => self.log_level = value.to_string(),
+            "pool-path" => self.pool.path = value.to_string(),
+            "pool-size" => self.pool.size = value.to_string(),
+            "index" => self.assets.index = PathBuf::from(value),
+            "tls-cert" | "tls-key" => {
+                let tls = self.tls.get_or_insert_with(|| Tls {
+                    cert: PathBuf::new(),
+                    key: PathBuf::new(),
+                    reload_interval: default_reload_interval(),
+                });
+                if key == "tls-cert" {
+                    tls.cert = PathBuf::from(value);
+                } else {
+                    tls.key = PathBuf::from(value);
+                }
+            }
+            _ => return Err(format!("unknown setting, expected one of {}", OVERRIDES.join(", "))),
+        }
+        Ok(())
+    }
+
+    /// Checks every setting, and resolves the addresses to listen on
+    fn validate(&mut self) -> Result<(), Error> {
+        let mut problems = vec![];
+
+        if self.host.is_empty() {
+            problems.push("host: must not be empty".to_string());
+        }
+        if self.port == 0 {
+            problems.push("port: must not be 0".to_string());
+        }
+        let bind = if self.bind.is_empty() {
+            vec![self.host.clone()]
+        } else {
+            self.bind.clone()
+        };
+        for addr in &bind {
+            match resolve(addr, self.port) {
+                Ok(addrs) => {
+                    for addr in addrs {
+                        if !self.addrs.contains(&addr) {
+                            self.addrs.push(addr);
+                        }
+                    }
+                }
+                Err(e) => problems.push(format!("bind: {}", e)),
+            }
+        }
+
+        for directive in self.log_level.split(',').filter(|d| !d.is_empty()) {
+            let level = directive.rsplit('=').next().unwrap_or(directive);
+            if directive.contains('=') && !LOG_LEVELS.contains(&level.to_lowercase().as_str()) {
+                problems.push(format!(
+                    "log_level: unknown level {:?} in {:?}, expected one of {}",
+                    level,
+                    directive,
+                    LOG_LEVELS.join(", ")
+                ));
+            }
+        }
+
+        if self.pool.path.is_empty() {
+            problems.push("pool.path: must not be empty".to_string());
+        } else if let Some(dir) = Path::new(&self.pool.path).parent() {
+            if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                problems.push(format!("pool.path: {} is not a directory", dir.display()));
+            }
+        }
+        if !POOL_SIZES.iter().any(|(name, _)| name.eq_ignore_ascii_case(&self.pool.size)) {
+            let sizes: Vec<&str> = POOL_SIZES.iter().map(|(name, _)| *name).collect();
+            problems.push(format!(
+                "pool.size: unknown size {:?}, expected one of {}",
+                self.pool.size,
+                sizes.join(", ")
+            ));
+        }
+
+        if !self.assets.index.is_file() {
+            problems.push(format!("assets.index: {} is not a file", self.assets.index.display()));
+        }
+        if !self.assets.login.join("index.html").is_file() {
+            problems.push(format!(
+                "assets.login: {} has no index.html",
+                self.assets.login.display()
+            ));
+        }
+        for (prefix, dir) in &self.assets.dirs {
+            if prefix.is_empty()
+                || !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                problems.push(format!(
+                    "assets.dirs: {:?} must be a single path segment of letters, digits, _ and -",
+                    prefix
+                ));
+            } else if RESERVED_PATHS.contains(&prefix.as_str()) {
+                problems.push(format!("assets.dirs: /{} is taken by the server", prefix));
+            }
+            if !dir.is_dir() {
+                problems.push(format!("assets.dirs.{}: {} is not a directory", prefix, dir.display()));
+            }
+        }
+
+        if self.palette.is_empty() {
+            problems.push("palette: must have at least one color".to_string());
+        }
+
+        if let Some(tls) = &self.tls {
+            if let Err(e) = tls::check(tls) {
+                problems.push(format!("tls: {}", e));
+            }
+            if tls.reload_interval == 0 {
+                problems.push("tls.reload_interval: must be at least 1 second".to_string());
+            }
+        }
+
+        let limits = &self.limits;
+        if !(1..=1000).contains(&limits.tick_rate) {
+            problems.push("limits.tick_rate: must be between 1 and 1000".to_string());
+        }
+        if limits.ping_interval == 0 {
+            problems.push("limits.ping_interval: must be at least 1 second".to_string());
+        }
+        if limits.ping_timeout <= limits.ping_interval {
+            problems.push("limits.ping_timeout: must be longer than limits.ping_interval".to_string());
+        }
+        if limits.idle_timeout == 0 {
+            problems.push("limits.idle_timeout: must be at least 1 second".to_string());
+        }
+        if limits.max_message_len < 1024 {
+            problems.push("limits.max_message_len: must be at least 1024 bytes".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error(problems))
+        }
+    }
+
+    /// The color of the `id`th connection, until it picks one
+    pub fn color(&self, id: usize) -> u32 {
+        self.palette[id % self.palette.len()].0
+    }
+}
+
+/// Splits `--key value` and `--key=value` flags
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Vec<(String, String)>, Error> {
+    let mut flags = vec![];
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => return Err(Error::new(format!("unexpected argument {:?}", arg))),
+        };
+        let (key, value) = match flag.find('=') {
+            Some(i) => (flag[..i].to_string(), flag[i + 1..].to_string()),
+            None => match args.next() {
+                Some(value) => (flag.to_string(), value),
+                None => return Err(Error::new(format!("--{} needs a value", flag))),
+            },
+        };
+        if key != "config" && !OVERRIDES.contains(&key.as_str()) {
+            return Err(Error::new(format!(
+                "unknown flag --{}, expected --config or one of --{}",
+                key,
+                OVERRIDES.join(", --")
+            )));
+        }
+        flags.push((key, value));
+    }
+    Ok(flags)
+}
+
+/// Resolves an address to listen on, which may be an IP address or a host
+/// name, with or without a port
+fn resolve(addr: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    let bare = addr.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = bare.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let addrs: Vec<SocketAddr> = match addr.to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(_) => (addr, port)
+            .to_socket_addrs()
+            .map_err(|e| format!("cannot resolve {:?}: {}", addr, e))?
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err(format!("{:?} resolves to no address", addr));
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    /// Writes `json` to a configuration file of its own
+    fn file(name: &str, json: &str) -> String {
+        let path = env::temp_dir().join(format!("wss-{}-{}.json", name, std::process::id()));
+        fs::write(&path, json).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn load(args: &[&str], vars: &[(&str, &str)]) -> Result<Config, Error> {
+        let args = args.iter().map(|arg| arg.to_string());
+        Config::load_from(args, |name| {
+            vars.iter().find(|(n, _)| *n == name).map(|(_, value)| value.to_string())
+        })
+    }
+
+    fn config(json: &str) -> Config {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn problems(json: &str) -> Vec<String> {
+        config(json).validate().unwrap_err().0
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let path = file("precedence", r#"{ "host": "127.0.0.1", "port": 1, "log_level": "info" }"#);
+
+        let config = load(&["--config", &path], &[]).unwrap();
+        assert_eq!((config.host.as_str(), config.port), ("127.0.0.1", 1));
+        assert_eq!(config.log_level, "info");
+
+        let vars = [("WB_PORT", "2"), ("WB_LOG_LEVEL", "debug")];
+        let config = load(&["--config", &path], &vars).unwrap();
+        assert_eq!((config.port, config.log_level.as_str()), (2, "debug"));
+
+        let config = load(&["--config", &path, "--port=3"], &vars).unwrap();
+        assert_eq!((config.port, config.log_level.as_str()), (3, "debug"));
+        assert_eq!(config.addrs, vec![SocketAddr::from(([127, 0, 0, 1], 3))]);
+
+        // The file itself may come from the environment
+        let config = load(&[], &[("WB_CONFIG", &path)]).unwrap();
+        assert_eq!(config.port, 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bad_overrides_and_flags_are_rejected() {
+        let path = file("overrides", r#"{ "host": "127.0.0.1", "port": 1 }"#);
+        let e = load(&["--config", &path], &[("WB_PORT", "x")]).unwrap_err();
+        assert_eq!(e.0, vec![r#"WB_PORT: invalid port "x""#]);
+        assert!(load(&["--config", &path, "--color", "red"], &[]).is_err());
+        assert!(load(&["--config", &path, "--port"], &[]).is_err());
+        assert!(load(&["--config", &path, "stray"], &[]).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let json = r#"{ "host": "127.0.0.1", "port": 1, "prot": 2 }"#;
+        assert!(serde_json::from_str::<Config>(json).is_err());
+        let json = r#"{ "host": "127.0.0.1", "port": 1, "palette": ["red"] }"#;
+        assert!(serde_json::from_str::<Config>(json).is_err());
+    }
+
+    #[test]
+    fn validate_reports_every_bad_value() {
+        let problems = problems(
+            r#"{
+                "host": "",
+                "port": 0,
+                "log_level": "warn,wss=loud",
+                "pool": { "path": "missing/users.pool", "size": "3GB" },
+                "assets": { "index": "missing.html", "dirs": { "api": "src", "a/b": "src" } },
+                "palette": [],
+                "limits": { "tick_rate": 0, "ping_interval": 20, "ping_timeout": 20, "max_message_len": 10 }
+            }"#,
+        );
+        let fields: Vec<&str> = problems.iter().map(|p| p.split(':').next().unwrap()).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "host",
+                "port",
+                "bind",
+                "log_level",
+                "pool.path",
+                "pool.size",
+                "assets.index",
+                "assets.dirs",
+                "assets.dirs",
+                "palette",
+                "limits.tick_rate",
+                "limits.ping_timeout",
+                "limits.max_message_len",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_accepts_the_defaults() {
+        let mut config = config(r#"{ "host": "127.0.0.1", "port": 3035 }"#);
+        config.validate().unwrap();
+        assert_eq!(config.log_level, "warn,wss=info");
+        assert_eq!(config.pool.flags(), O_CFNE | O_2GB);
+    }
+
+    #[test]
+    fn bind_addresses_are_resolved_once_each() {
+        let mut config = config(
+            r#"{ "host": "unused", "port": 3035, "bind": ["127.0.0.1", "127.0.0.1:3035", "[::1]:4000"] }"#,
+        );
+        config.validate().unwrap();
+        assert_eq!(
+            config.addrs,
+            vec![
+                SocketAddr::from(([127, 0, 0, 1], 3035)),
+                SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4000),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_takes_addresses_and_names() {
+        let v4 = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 80);
+        let v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 80);
+        assert_eq!(resolve("127.0.0.1", 80), Ok(vec![v4]));
+        assert_eq!(resolve("127.0.0.1:80", 1), Ok(vec![v4]));
+        assert_eq!(resolve("::1", 80), Ok(vec![v6]));
+        assert_eq!(resolve("[::1]", 80), Ok(vec![v6]));
+        assert_eq!(resolve("[::1]:80", 1), Ok(vec![v6]));
+
+        let local = resolve("localhost", 80).unwrap();
+        assert!(!local.is_empty());
+        assert!(local.iter().all(|addr| addr.ip().is_loopback() && addr.port() == 80));
+        assert!(resolve("localhost:81", 80).unwrap().iter().all(|addr| addr.port() == 81));
+        assert!(resolve("no-such-host.invalid", 80).is_err());
+    }
+}
@@ -188,6 +188,45 @@ where
         true
     }
 
+    pub fn remove(&mut self, key: &K, j: &Journal) -> bool {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let index = (hasher.finish() as usize) % BUCKETS_MAX;
+
+        let slot = {
+            let mut bucket = self.buckets[index].borrow_mut(j);
+            let mut found = None;
+            for (i, e) in bucket.as_slice().iter().enumerate() {
+                if e.borrow().0 == *key {
+                    found = Some(i);
+                    break;
+                }
+            }
+            if let Some(i) = found {
+                let slot = bucket[i].borrow().1;
+                bucket.swap_remove(i);
+                slot
+            } else {
+                return false;
+            }
+        };
+
+        // The last value moves into the freed slot; point its key to it
+        let last = self.values.len() - 1;
+        if slot != last {
+            'outer: for i in 0..BUCKETS_MAX {
+                for e in &*self.buckets[i].borrow() {
+                    if e.borrow().1 == last {
+                        e.borrow_mut(j).1 = slot;
+                        break 'outer;
+                    }
+                }
+            }
+        }
+        self.values.swap_remove(slot);
+        true
+    }
+
     pub fn foreach<F: FnMut(&K, &V) -> ()>(&self, mut f: F) {
         for i in 0..BUCKETS_MAX {
             for e in &*self.buckets[i].borrow() {
@@ -213,3 +252,65 @@ where
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Map = PMutex<HashMap<u64, u64>>;
+
+    fn bucket(key: u64) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() as usize) % BUCKETS_MAX
+    }
+
+    fn check(map: &HashMap<u64, u64>, keys: &[u64]) {
+        for &key in keys {
+            assert_eq!(map.get(key), Some(key * 10), "key {}", key);
+        }
+        let mut count = 0;
+        map.foreach(|_, _| count += 1);
+        assert_eq!(count, keys.len());
+    }
+
+    /// The pool is global to the process, so every case shares one test
+    #[test]
+    fn remove_keeps_the_other_keys_reachable() {
+        let path = std::env::temp_dir().join(format!("wss-hashmap-{}.pool", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let root = P::open::<Map>(path.to_str().unwrap(), O_CFNE | O_1GB).unwrap();
+
+        // Keys 0 and 1 are the first values, the largest the last one;
+        // `colliding` all land in the bucket of key 0
+        let colliding: Vec<u64> = (40..1000).filter(|&k| bucket(k) == bucket(0)).take(3).collect();
+
+        P::transaction(|j| {
+            let mut keys: Vec<u64> = (0..40).chain(colliding.iter().copied()).collect();
+            let mut map = root.lock(j);
+            for &key in &keys {
+                map.put(key, key * 10, j);
+            }
+            check(&map, &keys);
+
+            let removals = [0, 20, *keys.last().unwrap(), colliding[0], 1, colliding[1]];
+            for key in removals.iter() {
+                assert!(map.remove(key, j));
+                assert!(!map.remove(key, j));
+                assert_eq!(map.get(*key), None);
+                keys.retain(|k| k != key);
+                check(&map, &keys);
+            }
+
+            // The freed slots are reused without clobbering anything
+            map.put(0, 0, j);
+            map.put(1000, 10000, j);
+            keys.extend(&[0, 1000]);
+            check(&map, &keys);
+        })
+        .unwrap();
+
+        drop(root);
+        let _ = std::fs::remove_file(&path);
+    }
+}
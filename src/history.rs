@@ -57,11 +57,16 @@ pub struct History {
 
 impl History {
     pub fn add(&self, j: &Journal, points: &[(i32,i32)], color: u32) {
+        self.add_at(j, SystemTime::now(), points, color)
+    }
+
+    /// Adds a line drawn at `ts`, such as one copied from another pool
+    pub fn add_at(&self, j: &Journal, ts: SystemTime, points: &[(i32,i32)], color: u32) {
         let mut current = self.current.borrow_mut(j);
         if let Some(curr) = current.upgrade(j) {
             let mut next = curr.next.borrow_mut(j);
             let new = Prc::new(Line {
-                ts: SystemTime::now(),
+                ts,
                 next: PRefCell::new(None),
                 prev: Prc::downgrade(&curr, j),
                 color,
@@ -72,7 +77,7 @@ impl History {
         } else {
             let mut head = self.head.borrow_mut(j);
             let new = Prc::new(Line {
-                ts: SystemTime::now(),
+                ts,
                 next: PRefCell::new(None),
                 prev: PWeak::new(),
                 color,
@@ -137,6 +142,35 @@ impl History {
         }
     }
 
+    pub fn current(&self, j: &Journal) -> Option<Prc<Line>> {
+        self.current.borrow().upgrade(j)
+    }
+
+    /// Visits the lines that are currently visible, i.e. from the head up to
+    /// and including the current line, in drawing order.
+    pub fn foreach_visible<F: FnMut(&Line)>(&self, j: &Journal, mut f: F) {
+        let last = self.last_timestamp(j);
+        let mut curr = self.head();
+        while let Some(item) = curr.promote(j) {
+            if item.timestamp() > last {
+                break;
+            }
+            f(&*item);
+            curr = item.next();
+        }
+    }
+
+    /// Visits every line, undone ones included, in drawing order, along with
+    /// whether it is currently visible.
+    pub fn foreach<F: FnMut(&Line, bool)>(&self, j: &Journal, mut f: F) {
+        let last = self.last_timestamp(j);
+        let mut curr = self.head();
+        while let Some(item) = curr.promote(j) {
+            f(&*item, item.timestamp() <= last);
+            curr = item.next();
+        }
+    }
+
     pub fn last_timestamp(&self, j: &Journal) -> SystemTime {
         if let Some(last) = self.current.borrow().upgrade(j) {
             last.ts
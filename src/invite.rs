@@ -0,0 +1,143 @@
+use crate::hashmap::HashMap as PHashMap;
+use crate::role::Role;
+use crate::session::now;
+use corundum::default::*;
+use hex::{FromHex, ToHex};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::convert::TryInto;
+
+type P = BuddyAlloc;
+type HmacSha256 = Hmac<Sha256>;
+
+/// The longest an invite can stay valid, in seconds
+pub const MAX_INVITE_TTL: u64 = 30 * 24 * 60 * 60;
+
+/// The length of the payload of an invite token: the id, the board, the role
+/// and the expiry
+const INVITE_LEN: usize = 16 + 16 + 1 + 8;
+
+/// Starts the payload of guest tokens, followed by the invite and guest ids
+const GUEST: u8 = b'g';
+const GUEST_LEN: usize = 1 + 16 + 16;
+
+/// Access to a board handed out without an account
+#[derive(Clone, Copy)]
+pub struct Invite {
+    pub board: [u8; 16],
+    pub role: Role,
+    pub expires: u64,
+    /// How many more times it can be redeemed
+    pub uses_left: u32,
+}
+
+/// The invites that are neither revoked nor expired, keyed by a random id.
+///
+/// Tokens carry the id, the board, the role and the expiry, signed with a
+/// secret kept in the pool, so forged or tampered tokens are turned away
+/// before any lookup. Guests keep their access until the invite expires or
+/// is revoked, even once it has no uses left.
+///
+/// Redeeming an invite also hands the guest a token of their own, signed the
+/// same way, which brings them back under the same id when they reconnect
+/// without using the invite up again.
+pub struct Invites {
+    secret: [u8; 32],
+    invites: PHashMap<[u8; 16], Invite>,
+}
+
+impl RootObj<P> for Invites {
+    fn init(j: &Journal) -> Self {
+        Invites {
+            secret: rand::random(),
+            invites: RootObj::init(j),
+        }
+    }
+}
+
+impl Invites {
+    /// Stores a new invite and returns its signed token
+    pub fn create(&mut self, invite: Invite, j: &Journal) -> String {
+        self.purge(j);
+        let id: [u8; 16] = rand::random();
+        self.invites.put(id, invite, j);
+
+        let mut payload = id.to_vec();
+        payload.extend_from_slice(&invite.board);
+        payload.push(invite.role as u8);
+        payload.extend_from_slice(&invite.expires.to_be_bytes());
+        self.sign(&payload)
+    }
+
+    /// Returns the token that brings `guest` back with the invite `id`
+    pub fn admit(&self, id: &[u8; 16], guest: &[u8; 16]) -> String {
+        let mut payload = vec![GUEST];
+        payload.extend_from_slice(id);
+        payload.extend_from_slice(guest);
+        self.sign(&payload)
+    }
+
+    fn sign(&self, payload: &[u8]) -> String {
+        let tag = self.mac(payload).finalize().into_bytes();
+        format!("{}.{}", payload.encode_hex::<String>(), tag.encode_hex::<String>())
+    }
+
+    fn mac(&self, payload: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(payload);
+        mac
+    }
+
+    /// Returns the payload of a token, if it is signed with our secret
+    fn open(&self, token: &str) -> Option<Vec<u8>> {
+        let mut parts = token.splitn(2, '.');
+        let payload = Vec::<u8>::from_hex(parts.next()?).ok()?;
+        let tag = Vec::<u8>::from_hex(parts.next()?).ok()?;
+        self.mac(&payload).verify_slice(&tag).ok()?;
+        Some(payload)
+    }
+
+    /// Checks the signature of a token and returns the id of its invite
+    pub fn verify(&self, token: &str) -> Option<[u8; 16]> {
+        let payload = self.open(token).filter(|p| p.len() == INVITE_LEN)?;
+        payload[..16].try_into().ok()
+    }
+
+    /// Checks the signature of a guest token and returns the id of its
+    /// invite and the id of the guest
+    pub fn verify_guest(&self, token: &str) -> Option<([u8; 16], [u8; 16])> {
+        let payload = self.open(token).filter(|p| p.len() == GUEST_LEN && p[0] == GUEST)?;
+        Some((payload[1..17].try_into().ok()?, payload[17..].try_into().ok()?))
+    }
+
+    /// Returns the invite, unless it was revoked or has expired
+    pub fn check(&self, id: &[u8; 16]) -> Option<Invite> {
+        self.invites.get(*id).filter(|i| i.expires > now())
+    }
+
+    /// Uses the invite up once, and returns it if it was still valid
+    pub fn redeem(&mut self, id: &[u8; 16], j: &Journal) -> Option<Invite> {
+        let mut invite = self.check(id).filter(|i| i.uses_left > 0)?;
+        invite.uses_left -= 1;
+        self.invites.put(*id, invite, j);
+        Some(invite)
+    }
+
+    pub fn revoke(&mut self, id: &[u8; 16], j: &Journal) -> bool {
+        self.invites.remove(id, j)
+    }
+
+    /// Drops the expired invites
+    pub fn purge(&mut self, j: &Journal) {
+        let now = now();
+        let mut expired = vec![];
+        self.invites.foreach(|id, i| {
+            if i.expires <= now {
+                expired.push(*id);
+            }
+        });
+        for id in expired {
+            self.invites.remove(&id, j);
+        }
+    }
+}
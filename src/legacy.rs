@@ -0,0 +1,92 @@
+use crate::hashmap::HashMap as PHashMap;
+use crate::history::History;
+use corundum::default::*;
+use std::time::SystemTime;
+
+type P = BuddyAlloc;
+
+/// A user as the first version of the server stored them: with the
+/// unsalted MD5 digest of their password, and a single history on the only
+/// board there was
+struct UserInfo {
+    username: PString,
+    password: [u8; 16],
+    color: u32,
+    history: History,
+}
+
+impl RootObj<P> for UserInfo {
+    fn init(j: &Journal) -> Self {
+        UserInfo {
+            username: Default::default(),
+            password: Default::default(),
+            color: 0,
+            history: RootObj::init(j),
+        }
+    }
+}
+
+/// The root of a legacy pool. Its only field was laid out first without
+/// `#[repr(C)]` as well, so `LAYOUT` is compared with the start of the map.
+#[repr(C)]
+struct Database {
+    data: PHashMap<[u8; 16], UserInfo>,
+}
+
+impl RootObj<P> for Database {
+    fn init(j: &Journal) -> Self {
+        Database {
+            data: RootObj::init(j),
+        }
+    }
+}
+
+type Root = Parc<PMutex<Database>>;
+
+/// A user of a legacy pool, copied out of it
+pub struct User {
+    pub id: [u8; 16],
+    pub name: String,
+    /// The MD5 digest of the password
+    pub digest: [u8; 16],
+    pub color: u32,
+    /// Every line of the history, undone ones included, in drawing order:
+    /// when it was drawn, its color and its points
+    pub lines: Vec<(SystemTime, u32, Vec<(i32, i32)>)>,
+    /// How many of the lines are visible; the others were undone
+    pub visible: usize,
+}
+
+/// Reads every user out of a pool written by the first version of the
+/// server, and closes it
+pub fn read(path: &str, flags: u32) -> Result<Vec<User>, String> {
+    let root = P::open::<Root>(path, flags).map_err(|e| format!("cannot open {}: {}", path, e))?;
+    let users = P::transaction(|j| {
+        let mut users = vec![];
+        let root = root.lock(j);
+        root.data.foreach(|id, u| {
+            let mut lines = vec![];
+            let mut visible = 0;
+            u.history.foreach(j, |line, shown| {
+                if shown {
+                    visible += 1;
+                }
+                lines.push((line.timestamp(), line.color(), line.points()));
+            });
+            users.push(User {
+                id: *id,
+                name: u.username.as_str().to_string(),
+                digest: u.password,
+                color: u.color,
+                lines,
+                visible,
+            });
+        });
+        users
+    })
+    .map_err(|e| format!("cannot read {}: {}", path, e))?;
+
+    drop(root);
+    unsafe { P::close() }.map_err(|e| format!("cannot close {}: {}", path, e))?;
+    Ok(users)
+}
@@ -0,0 +1,149 @@
+use crate::hashmap::HashMap as PHashMap;
+use crate::session::now;
+use corundum::default::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+type P = BuddyAlloc;
+
+/// Failed logins allowed before backing off
+const FREE_ATTEMPTS: u32 = 3;
+
+/// The delay after the first failure beyond the free ones, in seconds. It
+/// doubles with every further failure, up to `MAX_DELAY`.
+const BASE_DELAY: u64 = 2;
+
+const MAX_DELAY: u64 = 15 * 60;
+
+/// After this many consecutive failures, the account is locked for
+/// `LOCKOUT` seconds, or until an admin unlocks it.
+const LOCKOUT_AFTER: u32 = 10;
+
+const LOCKOUT: u64 = 60 * 60;
+
+/// Consecutive failed logins, for an account or a connection
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Attempts {
+    failures: u32,
+    /// No attempt is accepted before this time
+    until: u64,
+}
+
+impl Attempts {
+    /// Returns how many seconds to wait before the next attempt, if any
+    pub fn blocked(&self) -> Option<u64> {
+        let now = now();
+        if self.until > now {
+            Some(self.until - now)
+        } else {
+            None
+        }
+    }
+
+    pub fn fail(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+        let delay = if self.failures >= LOCKOUT_AFTER {
+            LOCKOUT
+        } else if self.failures > FREE_ATTEMPTS {
+            let exp = (self.failures - FREE_ATTEMPTS - 1).min(16);
+            (BASE_DELAY << exp).min(MAX_DELAY)
+        } else {
+            0
+        };
+        self.until = now() + delay;
+    }
+}
+
+/// The failed logins over HTTP of every client address, kept in memory like
+/// those of a WebSocket connection
+#[derive(Default)]
+pub struct ClientLockouts {
+    clients: Mutex<HashMap<IpAddr, Attempts>>,
+}
+
+impl ClientLockouts {
+    /// Returns how many seconds the client has to wait before the next login
+    /// attempt, if any
+    pub fn blocked(&self, client: IpAddr) -> Option<u64> {
+        self.clients.lock().unwrap().get(&client).and_then(|a| a.blocked())
+    }
+
+    pub fn fail(&self, client: IpAddr) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() > 4096 {
+            // Forget the clients that may try again anyway
+            clients.retain(|_, a| a.blocked().is_some());
+        }
+        clients.entry(client).or_default().fail();
+    }
+
+    pub fn reset(&self, client: IpAddr) {
+        self.clients.lock().unwrap().remove(&client);
+    }
+}
+
+/// The failed logins of every account that has some
+pub struct Lockouts {
+    accounts: PHashMap<[u8; 16], Attempts>,
+}
+
+impl RootObj<P> for Lockouts {
+    fn init(j: &Journal) -> Self {
+        Lockouts {
+            accounts: RootObj::init(j),
+        }
+    }
+}
+
+impl Lockouts {
+    /// Returns how many seconds the account has to wait before the next
+    /// login attempt, if any
+    pub fn blocked(&self, user: [u8; 16]) -> Option<u64> {
+        self.accounts.get(user).and_then(|a| a.blocked())
+    }
+
+    pub fn fail(&mut self, user: [u8; 16], j: &Journal) {
+        let mut attempts = self.accounts.get(user).unwrap_or_default();
+        attempts.fail();
+        self.accounts.put(user, attempts, j);
+    }
+
+    /// Forgets the failures, after a successful login or an admin's unlock
+    pub fn reset(&mut self, user: [u8; 16], j: &Journal) -> bool {
+        self.accounts.remove(&user, j)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const A: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const B: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn clients_back_off_after_the_free_attempts() {
+        let clients = ClientLockouts::default();
+        for _ in 0..FREE_ATTEMPTS {
+            clients.fail(A);
+            assert_eq!(clients.blocked(A), None);
+        }
+        clients.fail(A);
+        // Give or take the second that may tick in between
+        assert!(matches!(clients.blocked(A), Some(wait) if wait <= BASE_DELAY));
+        assert_eq!(clients.blocked(B), None);
+        clients.reset(A);
+        assert_eq!(clients.blocked(A), None);
+    }
+
+    #[test]
+    fn clients_are_locked_out_after_too_many_failures() {
+        let clients = ClientLockouts::default();
+        for _ in 0..LOCKOUT_AFTER {
+            clients.fail(A);
+        }
+        assert!(matches!(clients.blocked(A), Some(wait) if wait > LOCKOUT - 2));
+    }
+}
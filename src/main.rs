@@ -1,59 +1,98 @@
 // #![deny(warnings)]
 #![allow(dead_code)]
-use std::path::Path;
-use std::io::BufReader;
-use std::fs::File;
-use futures::{FutureExt, StreamExt};
+use futures::StreamExt;
 use corundum::default::*;
-use serde_json::{json, Result as Rslt, Value};
-use std::collections::BTreeMap;
-use std::collections::HashMap;
+use log::{error, info, warn};
+use std::env;
+use std::fs;
+use std::path::Path;
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
     Arc,
 };
-use std::time::SystemTime;
-use tokio::sync::{mpsc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::RwLock;
 use warp::ws::{Message, WebSocket};
 use warp::Filter;
 use md5::*;
 use hex::*;
 use serde::*;
 
+mod account;
+mod api;
+mod board;
+mod chat;
+mod config;
 mod hashmap;
 mod history;
+mod invite;
+mod legacy;
+mod lockout;
+mod metrics;
+mod oplog;
+mod outbox;
+mod password;
+mod protocol;
+mod ratelimit;
+mod role;
+mod room;
+mod session;
+mod svg;
+mod tls;
+mod validate;
+use board::{Change, Snapshot};
+use chat::Chat;
+use config::Config;
 use hashmap::HashMap as PHashMap;
 use history::*;
+use invite::{Invite, Invites};
+use lockout::{Attempts, ClientLockouts, Lockouts};
+use metrics::{Metrics, METRICS};
+use oplog::{replay, OpLog};
+use outbox::{Kind, Outbox};
+use password::{Hash, Password};
+use protocol::{ChatEntry, ClientMsg, Member, ServerMsg, PROTOCOL_VERSION};
+use ratelimit::{Bucket, Class, Limiter, UserLimits, STRIKES};
+use role::Role;
+use room::{send, Client, Room, Rooms, Tx};
+use session::Sessions;
+use tls::Routes;
+use validate::Invalid;
 
 /// Our global unique user id counter.
 static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
 
-const fn color(r: u8, g: u8, b: u8) -> u32 {
-    ((r as u32) << 16) | ((g as u32) << 8) | (b as u32)
-}
+/// Cursor positions closer together than this are dropped
+const CURSOR_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long clients are given to receive the last messages on shutdown
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
 
-static COLOR_PALLETE: [u32; 8] = [
-    color(0, 0, 0),
-    color(255, 0, 0),
-    color(0, 255, 0),
-    color(0, 0, 255),
-    color(128, 128, 0),
-    color(128, 0, 128),
-    color(0, 128, 128),
-    color(64, 64, 64),
-];
+/// The board served at `/wb`. It is created on startup if missing, and can be
+/// neither renamed nor deleted. It has no owner but the admins, and everyone
+/// may draw on it.
+const DEFAULT_BOARD: &str = "main";
 
-#[derive(Deserialize, Debug)]
-struct Server {
-    host: String,
-    port: u16
+/// Marks the pools written by this version of the server, in the first word
+/// of their root; `Database` is `#[repr(C)]` to keep it there. Pools written
+/// by the first version start with the user map instead, and are converted
+/// on startup.
+const LAYOUT_MAGIC: u64 = 0x5742_504f_4f4c_0000;
+const LAYOUT: u64 = LAYOUT_MAGIC | 1;
+
+/// The query string of the WebSocket route
+#[derive(Deserialize, Default)]
+struct WsParams {
+    /// Only watch the board, e.g. to project it during a meeting
+    #[serde(default)]
+    spectate: bool,
 }
 
 struct UserInfo {
     username: PString,
-    password: [u8; 16],
+    password: Password,
     color: u32,
-    history: History,
 }
 
 impl RootObj<P> for UserInfo {
@@ -62,348 +101,1889 @@ impl RootObj<P> for UserInfo {
             username: Default::default(),
             password: Default::default(),
             color: 0,
-            history: RootObj::init(j),
         }
     }
 }
 
-/// Our state of currently connected users.
-///
-/// - Key is their id
-/// - Value is a sender of `warp::ws::Message`
-type Users = Arc<RwLock<HashMap<usize, mpsc::UnboundedSender<Result<Message, warp::Error>>>>>;
+struct BoardInfo {
+    name: PString,
+    /// The drawing history of every user who has drawn on the board
+    histories: PHashMap<[u8; 16], History>,
+    oplog: OpLog,
+    /// The roles granted on the board
+    roles: PHashMap<[u8; 16], Role>,
+    /// The role of the users who were granted none
+    default_role: Role,
+    chat: Chat,
+}
 
+impl RootObj<P> for BoardInfo {
+    fn init(j: &Journal) -> Self {
+        BoardInfo {
+            name: Default::default(),
+            histories: RootObj::init(j),
+            oplog: RootObj::init(j),
+            roles: RootObj::init(j),
+            default_role: Role::Viewer,
+            chat: RootObj::init(j),
+        }
+    }
+}
+
+/// The state of a single WebSocket connection
+struct Connection {
+    /// The key of this connection in the clients of its room
+    id: usize,
+    tx: Tx,
+    /// The board this connection is on
+    room: Arc<Room>,
+    /// The user on whose behalf this connection draws
+    user: [u8; 16],
+    /// The token of the session the user signed in with
+    session: Option<[u8; 16]>,
+    /// The invite a guest joined with, instead of signing in
+    invite: Option<[u8; 16]>,
+    /// The color a guest draws with; users keep theirs in the pool
+    color: u32,
+    /// Whether the client has completed the `hello` handshake
+    greeted: bool,
+    /// The failed logins on this connection, whatever the account
+    attempts: Attempts,
+    /// Whether the connection only watches the board
+    spectator: bool,
+    /// When the last cursor position was relayed
+    last_cursor: Option<Instant>,
+    /// The rate limits of this connection
+    limiter: Limiter,
+    /// The rate limits of every user, shared with their other connections
+    limits: Limits,
+    /// Broken limits; the connection is kicked when it runs out
+    strikes: Bucket,
+    /// When the client last sent a command
+    last_active: Instant,
+    config: &'static Config,
+    /// The session cookie sent along with the upgrade request, if any
+    cookie: Option<String>,
+}
+
+/// What a connection proves who it is with, copied out of it so that it can
+/// be checked inside a transaction
+#[derive(Clone, Copy)]
+struct Credentials {
+    user: [u8; 16],
+    session: Option<[u8; 16]>,
+    invite: Option<[u8; 16]>,
+}
+
+impl Connection {
+    fn credentials(&self) -> Result<Credentials, Invalid> {
+        if self.session.is_none() && self.invite.is_none() {
+            return Err(not_signed_in());
+        }
+        Ok(Credentials {
+            user: self.user,
+            session: self.session,
+            invite: self.invite,
+        })
+    }
+
+    /// Sends a message to this connection only
+    fn reply(&self, msg: String) {
+        send(&self.tx, msg);
+    }
+
+    /// Reports a rejected request back to the client
+    fn reject(&self, e: Invalid) {
+        info!("User<#{}> sent a bad request: {}", self.id, e);
+        self.reply(
+            ServerMsg::Error {
+                code: e.code,
+                message: e.message,
+            }
+            .to_string(),
+        );
+    }
+}
+
+/// The volatile state of every board, by name
+type Registry = Arc<RwLock<Rooms>>;
+
+type Limits = Arc<UserLimits>;
+
+/// Held for reading while a command is processed, and for writing on
+/// shutdown, once no command is anymore
+type Gate = Arc<RwLock<()>>;
+
+/// The ids of the admin users
+type Admins = Arc<Vec<[u8; 16]>>;
+
+/// What every connection and HTTP request shares
+#[derive(Clone)]
+struct App {
+    root: RootPack,
+    registry: Registry,
+    admins: Admins,
+    limits: Limits,
+    /// The rate limits of HTTP clients by address, apart from those of users
+    clients: Limits,
+    /// The failed logins of HTTP clients by address
+    client_lockouts: Arc<ClientLockouts>,
+    gate: Gate,
+    config: &'static Config,
+}
+
+#[repr(C)]
 struct Database {
-    data: PHashMap<[u8; 16], UserInfo>
+    /// Always `LAYOUT`, and always first
+    layout: u64,
+    data: PHashMap<[u8; 16], UserInfo>,
+    /// The boards, keyed by a random id that survives renames
+    boards: PHashMap<[u8; 16], BoardInfo>,
+    sessions: Sessions,
+    lockouts: Lockouts,
+    invites: Invites,
 }
 
 impl RootObj<P> for Database {
     fn init(j: &Journal) -> Self {
         Database {
-            data: RootObj::init(j)
+            layout: LAYOUT,
+            data: RootObj::init(j),
+            boards: RootObj::init(j),
+            sessions: RootObj::init(j),
+            lockouts: RootObj::init(j),
+            invites: RootObj::init(j),
         }
     }
 }
 
+impl Database {
+    /// Creates an account, unless the user already exists
+    fn register(&mut self, user: [u8; 16], name: &str, hash: &Hash, color: u32, j: &Journal) -> bool {
+        if self.data.get_ref(user).is_some() {
+            return false;
+        }
+        self.data.put(
+            user,
+            UserInfo {
+                username: name.to_pstring(j),
+                password: Password::new(hash, j),
+                color,
+            },
+            j,
+        );
+        true
+    }
+
+    fn set_password(&self, user: [u8; 16], hash: &Hash, j: &Journal) -> bool {
+        self.data.update_inplace_mut(&user, j, |u| u.password = Password::new(hash, j))
+    }
+
+    /// Changes the display name of the user; the name used to sign in stays
+    /// the same
+    fn rename(&self, user: [u8; 16], name: &str, j: &Journal) -> bool {
+        self.data.update_inplace_mut(&user, j, |u| u.username = name.to_pstring(j))
+    }
+
+    /// Deletes the account along with its sessions. Its histories are left
+    /// to the caller, which has to take the lines off the boards.
+    fn delete_user(&mut self, user: [u8; 16], j: &Journal) -> bool {
+        self.sessions.close_all(user, None, j);
+        self.lockouts.reset(user, j);
+        self.data.remove(&user, j)
+    }
+
+    fn find_board(&self, name: &str) -> Option<[u8; 16]> {
+        let mut found = None;
+        self.boards.foreach(|id, b| {
+            if b.name.as_str() == name {
+                found = Some(*id);
+            }
+        });
+        found
+    }
+
+    /// Creates an empty board and returns its id, unless the name is taken
+    fn create_board(
+        &mut self,
+        name: &str,
+        owner: Option<[u8; 16]>,
+        default_role: Role,
+        j: &Journal,
+    ) -> Option<[u8; 16]> {
+        if self.find_board(name).is_some() {
+            return None;
+        }
+        let id: [u8; 16] = rand::random();
+        let mut roles = PHashMap::new(j);
+        if let Some(owner) = owner {
+            roles.put(owner, Role::Owner, j);
+        }
+        self.boards.put(
+            id,
+            BoardInfo {
+                name: name.to_pstring(j),
+                histories: RootObj::init(j),
+                oplog: RootObj::init(j),
+                roles,
+                default_role,
+                chat: RootObj::init(j),
+            },
+            j,
+        );
+        Some(id)
+    }
+
+    /// The role of the user on the board, if the board exists
+    fn role(&self, board: [u8; 16], user: [u8; 16]) -> Option<Role> {
+        self.boards
+            .get_ref(board)
+            .map(|b| b.roles.get(user).unwrap_or(b.default_role))
+    }
+
+    /// Grants a role on the board, or takes it back with `None`
+    fn set_role(&self, board: [u8; 16], user: [u8; 16], role: Option<Role>, j: &Journal) -> bool {
+        self.boards.update_inplace_mut(&board, j, |b| match role {
+            Some(role) => b.roles.put(user, role, j),
+            None => {
+                b.roles.remove(&user, j);
+            }
+        })
+    }
+
+    fn rename_board(&self, id: [u8; 16], name: &str, j: &Journal) -> bool {
+        self.boards.update_inplace_mut(&id, j, |b| b.name = name.to_pstring(j))
+    }
+
+    /// Deletes the board along with every history drawn on it
+    fn delete_board(&mut self, id: [u8; 16], j: &Journal) -> bool {
+        self.boards.remove(&id, j)
+    }
+}
+
 type Root = Parc<PMutex<Database>>;
 type RootPack = parc::VWeak<PMutex<Database>>;
 
-fn read_user_from_file<P: AsRef<Path>>(path: P) -> Result<Server, Box<dyn std::error::Error>> {
-    // Open the file in read-only mode with buffer.
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-
-    // Read the JSON contents of the file as an instance of `User`.
-    let u = serde_json::from_reader(reader)?;
+/// Opens the pool, and converts it first if the first version of the server
+/// wrote it. The original is kept next to it, as `<path>.legacy`.
+fn open_pool(config: &Config) -> Result<Root, String> {
+    let path = &config.pool.path;
+    let flags = config.pool.flags();
+    if !Path::new(path).exists() {
+        return P::open::<Root>(path, flags).map_err(|e| format!("cannot create {}: {}", path, e));
+    }
+    let root = P::open::<Root>(path, flags).map_err(|e| format!("cannot open {}: {}", path, e))?;
+    let layout = P::transaction(|j| root.lock(j).layout).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    if layout == LAYOUT {
+        return Ok(root);
+    }
+    if layout & !0xffff == LAYOUT_MAGIC {
+        return Err(format!(
+            "{} has layout {}, which this version of the server cannot read",
+            path,
+            layout & 0xffff
+        ));
+    }
+    drop(root);
+    unsafe { P::close() }.map_err(|e| format!("cannot close {}: {}", path, e))?;
 
-    // Return the `User`.
-    Ok(u)
+    let backup = format!("{}.legacy", path);
+    if Path::new(&backup).exists() {
+        return Err(format!("{} is not converted yet, but {} is in the way", path, backup));
+    }
+    info!("Converting {} to the current layout, keeping the original as {}", path, backup);
+    fs::rename(path, &backup).map_err(|e| format!("cannot rename {}: {}", path, e))?;
+    let users = legacy::read(&backup, flags)?;
+    let root = P::open::<Root>(path, flags).map_err(|e| format!("cannot create {}: {}", path, e))?;
+    P::transaction(|j| {
+        let mut root = root.lock(j);
+        let board = root
+            .find_board(DEFAULT_BOARD)
+            .or_else(|| root.create_board(DEFAULT_BOARD, None, Role::Editor, j));
+        for user in &users {
+            root.data.put(
+                user.id,
+                UserInfo {
+                    username: user.name.to_pstring(j),
+                    // Rehashed on the next successful login
+                    password: Password::legacy(&user.digest, j),
+                    color: user.color,
+                },
+                j,
+            );
+            // The lines go to the default board, the only one there was
+            let board = match board {
+                Some(board) if !user.lines.is_empty() => board,
+                _ => continue,
+            };
+            root.boards.update_inplace_mut(&board, j, |b| {
+                b.histories.put(user.id, RootObj::init(j), j);
+                if let Some(history) = b.histories.get_ref(user.id) {
+                    for (ts, color, points) in &user.lines {
+                        history.add_at(j, *ts, points, *color);
+                    }
+                    for _ in user.visible..user.lines.len() {
+                        history.undo();
+                    }
+                }
+            });
+        }
+    })
+    .map_err(|e| format!("cannot convert {}: {}", path, e))?;
+    info!("Converted {} users", users.len());
+    Ok(root)
 }
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    // Read by every connection for as long as the server runs
+    let config: &'static Config = Box::leak(Box::new(config));
+    // RUST_LOG, when set, wins over the configuration
+    let filters = env::var("RUST_LOG").unwrap_or_else(|_| config.log_level.clone());
+    pretty_env_logger::formatted_builder().parse_filters(&filters).init();
 
-    // Keep track of all connected users, key is usize, value
-    // is a websocket sender.
-    let users = Users::default();
-    // Turn our "state" into a new Filter...
-    let users = warp::any().map(move || users.clone());
+    let admins: Admins = Arc::new(config.admins.iter().map(|name| *compute(name)).collect());
 
-    let info = P::open::<Root>("users.pool", O_CFNE | O_2GB).unwrap();
+    let info = match open_pool(config) {
+        Ok(info) => info,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
     let pack = info.demote();
-    let db = warp::any().map(move || pack.clone());
-    // GET /wb -> websocket upgrade
-    let wb = warp::path("wb")
+
+    // Materialize the visible lines of every board once; from now on, the
+    // snapshots are updated incrementally by every command that changes them.
+    let boards = P::transaction(|j| {
+        let mut boards = vec![];
+        if let Some(root) = pack.promote(j) {
+            let mut root = root.lock(j);
+            if root.find_board(DEFAULT_BOARD).is_none() {
+                root.create_board(DEFAULT_BOARD, None, Role::Editor, j);
+            }
+            root.boards.foreach(|id, b| {
+                let mut snapshot = Snapshot::new(b.oplog.version());
+                b.histories.foreach(|user, history| {
+                    history.foreach_visible(j, |line| {
+                        snapshot.insert((line.timestamp(), *user), line.as_json());
+                    });
+                });
+                boards.push((b.name.as_str().to_string(), *id, snapshot));
+            });
+        }
+        boards
+    }).unwrap();
+    let mut rooms = Rooms::default();
+    for (name, id, snapshot) in boards {
+        rooms.insert(name, Arc::new(Room::new(id, snapshot)));
+    }
+    let registry = Registry::new(RwLock::new(rooms));
+    let rooms = registry.clone();
+
+    let gate = Gate::default();
+    let commands = gate.clone();
+
+    // Send the live strokes out in batches, once per tick
+    let tick = config.limits.tick();
+    let ticker = registry.clone();
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(tick);
+        loop {
+            interval.tick().await;
+            let rooms = ticker.read().await.all();
+            for room in rooms {
+                room.flush().await;
+            }
+        }
+    });
+
+    let app = App {
+        root: pack,
+        registry,
+        admins,
+        limits: Limits::default(),
+        clients: Limits::default(),
+        client_lockouts: Arc::default(),
+        gate,
+        config,
+    };
+    let app = warp::any().map(move || app.clone());
+    // GET /wb/<board> -> websocket upgrade, GET /wb for the default board.
+    // Add `?spectate=true` to only watch.
+    let wb = warp::path!("wb" / String)
+        .or(warp::path!("wb").map(|| DEFAULT_BOARD.to_string()))
+        .unify()
+        .and(warp::query::<WsParams>().or(warp::any().map(WsParams::default)).unify())
         // The `ws()` filter will prepare Websocket handshake...
         .and(warp::ws())
-        .and(users)
-        .and(db)
-        .map(|ws: warp::ws::Ws, users, db| {
+        .and(app.clone())
+        .and(warp::cookie::optional(account::COOKIE))
+        .map(|board: String, params: WsParams, ws: warp::ws::Ws, app: App, cookie| {
+            // Larger messages are refused before they are buffered in full
+            let max_len = app.config.limits.max_message_len;
+            let ws = ws.max_message_size(max_len).max_frame_size(max_len);
             // This will call our function if the handshake succeeds.
-            ws.on_upgrade(move |socket| user_connected(socket, users, db))
+            ws.on_upgrade(move |socket| user_connected(socket, board, params.spectate, app, cookie))
         });
 
     // GET / -> index html
-    let index = warp::path::end().map(|| warp::reply::html(
-        std::fs::read_to_string("wb.html")
+    let index = warp::path::end().map(move || warp::reply::html(
+        std::fs::read_to_string(&config.assets.index)
         .expect("Something went wrong reading the file")));
 
-    let routes = index.or(wb);
+    // GET /login/ -> the login page, and its assets below. The page is a
+    // template, so the file is never served as it is
+    // POST /login/session -> sign in, POST /login/user -> sign up
+    // GET /login/logout -> sign out
+    let login_page = warp::get()
+        .and(warp::path!("login").or(warp::path!("login" / "index.html")).unify())
+        .map(move || account::page(config));
+    let sign_in = warp::post()
+        .and(warp::path!("login" / "session"))
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::json())
+        .and(warp::addr::remote())
+        .and(app.clone())
+        .and_then(|form, remote, app| account::sign_in(form, false, remote, app));
+    let sign_up = warp::post()
+        .and(warp::path!("login" / "user"))
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::json())
+        .and(warp::addr::remote())
+        .and(app.clone())
+        .and_then(|form, remote, app| account::sign_in(form, true, remote, app));
+    let sign_out = warp::get()
+        .and(warp::path!("login" / "logout"))
+        .and(warp::cookie::optional(account::COOKIE))
+        .and(app.clone())
+        .and_then(account::sign_out);
+    let login_assets = warp::path("login").and(warp::fs::dir(config.assets.login.clone()));
+    let login = login_page
+        .or(sign_in)
+        .unify()
+        .or(sign_up)
+        .unify()
+        .or(sign_out)
+        .unify()
+        .or(login_assets);
+
+    // GET /api/boards/<board>/lines -> the visible lines of a board
+    // POST /api/boards/<board>/lines -> draw a line
+    // GET /api/boards/<board>/svg -> the visible lines of a board, as SVG
+    // GET /api/users/<name>/history -> every line a user drew
+    let auth = warp::header::optional::<String>("authorization")
+        .and(warp::cookie::optional(account::COOKIE));
+    let get_lines = warp::get()
+        .and(warp::path!("api" / "boards" / String / "lines"))
+        .and(auth.clone())
+        .and(app.clone())
+        .and_then(api::lines);
+    let post_line = warp::post()
+        .and(warp::path!("api" / "boards" / String / "lines"))
+        .and(warp::body::content_length_limit(config.limits.max_message_len as u64))
+        .and(warp::body::json())
+        .and(auth.clone())
+        .and(app.clone())
+        .and_then(api::add_line);
+    let get_svg = warp::get()
+        .and(warp::path!("api" / "boards" / String / "svg"))
+        .and(warp::query::<api::SvgQuery>().or(warp::any().map(api::SvgQuery::default)).unify())
+        .and(auth.clone())
+        .and(app.clone())
+        .and_then(api::export_svg);
+    let get_history = warp::get()
+        .and(warp::path!("api" / "users" / String / "history"))
+        .and(auth)
+        .and(app)
+        .and_then(api::history);
+    let api = get_lines
+        .or(post_line)
+        .unify()
+        .or(get_svg)
+        .unify()
+        .or(get_history)
+        .unify();
+
+    // GET /metrics -> counters, in the Prometheus text format
+    let metrics = warp::path!("metrics").map(|| METRICS.report());
 
-    let server = read_user_from_file("login/server.json")
-        .expect("server.json does not exist");
-    let arr: Vec<&str> = server.host.split(".").collect();
-    let host: [u8; 4] = [
-        arr[0].parse().unwrap(),
-        arr[1].parse().unwrap(),
-        arr[2].parse().unwrap(),
-        arr[3].parse().unwrap()
-        ];
+    // GET /<prefix>/... -> the asset directories, as they are
+    let mut assets = warp::any()
+        .and_then(|| async { Err::<warp::fs::File, warp::Rejection>(warp::reject::not_found()) })
+        .boxed();
+    for (prefix, dir) in &config.assets.dirs {
+        assets = assets
+            .or(warp::path(prefix.as_str()).and(warp::fs::dir(dir.clone())))
+            .unify()
+            .boxed();
+    }
+
+    let routes: Routes = index
+        .or(login)
+        .or(wb)
+        .or(api)
+        .or(metrics)
+        .or(assets)
+        .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+        .boxed();
+
+    // Every listener stops on the first signal
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    tokio::task::spawn(async move {
+        shutdown_signal().await;
+        let _ = stop.broadcast(true);
+    });
+    let mut listeners = vec![];
+    for addr in &config.addrs {
+        let stopped = stopped.clone();
+        match &config.tls {
+            Some(tls) => {
+                info!("WebSocket is running at {} over TLS", addr);
+                listeners.push(tokio::task::spawn(tls::serve(routes.clone(), *addr, tls, stopped)));
+            }
+            None => {
+                let mut stopped = stopped;
+                let (addr, serving) = warp::serve(routes.clone())
+                    .bind_with_graceful_shutdown(*addr, async move {
+                        while let Some(false) = stopped.recv().await {}
+                    });
+                info!("WebSocket is running at {}", addr);
+                listeners.push(tokio::task::spawn(serving));
+            }
+        }
+    }
+    for listener in listeners {
+        let _ = listener.await;
+    }
+
+    info!("Shutting down");
+    // Wait for the commands being processed, and keep new ones from starting
+    let _done = commands.write().await;
+    let msg = ServerMsg::ServerShutdown.to_string();
+    for room in rooms.read().await.all() {
+        room.broadcast(&msg).await;
+        room.close().await;
+    }
+    tokio::time::delay_for(SHUTDOWN_GRACE).await;
 
-    eprintln!("WebSocket is running at {}:{}", server.host, server.port);
+    // No transaction can start anymore
+    drop(info);
+    if let Err(e) = unsafe { P::close() } {
+        error!("could not close the pool: {}", e);
+    }
+}
 
-    warp::serve(routes).run((host, server.port)).await;
+/// Resolves on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let mut term = signal(SignalKind::terminate()).expect("cannot listen to SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
+    }
 }
 
-async fn user_connected(ws: WebSocket, users: Users, root: RootPack) {
+async fn user_connected(
+    ws: WebSocket,
+    board: String,
+    spectator: bool,
+    app: App,
+    cookie: Option<String>,
+) {
+    let config = app.config;
     // Use a counter to assign a new unique ID for this user.
     let my_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
-    let mut user_id: [u8; 16] = [0; 16];
 
-    eprintln!("new user: {}", my_id);
+    info!("new user: {}", my_id);
 
     // Split the socket into a sender and receive of messages.
     let (user_ws_tx, mut user_ws_rx) = ws.split();
 
-    // Use an unbounded channel to handle buffering and flushing of messages
+    // Use a bounded outbox to handle buffering and flushing of messages
     // to the websocket...
-    let (tx, rx) = mpsc::unbounded_channel();
-    tokio::task::spawn(rx.forward(user_ws_tx).map(|result| {
-        if let Err(e) = result {
-            eprintln!("websocket send error: {}", e);
+    let tx = Tx::new(Outbox::default());
+    let outbox = tx.clone();
+    tokio::task::spawn(async move { outbox.run(user_ws_tx).await });
+
+    let room = app.registry.read().await.get(&board);
+    let room = match room {
+        Some(room) => room,
+        None => {
+            let e = no_board();
+            send(&tx, ServerMsg::Error { code: e.code, message: e.message }.to_string());
+            tx.close();
+            return;
         }
-    }));
+    };
 
-    // Save the sender in our list of connected users.
-    users.write().await.insert(my_id, tx);
+    // Save the sender in the list of users connected to the board.
+    room.clients.write().await.insert(
+        my_id,
+        Client {
+            tx: tx.clone(),
+            spectator,
+            member: None,
+        },
+    );
+    announce_presence(&room).await;
+
+    let mut conn = Connection {
+        id: my_id,
+        tx,
+        room,
+        user: [0; 16],
+        session: None,
+        invite: None,
+        color: config.color(my_id - 1),
+        greeted: false,
+        attempts: Attempts::default(),
+        spectator,
+        last_cursor: None,
+        limiter: Limiter::default(),
+        limits: app.limits.clone(),
+        strikes: Bucket::new(STRIKES),
+        last_active: Instant::now(),
+        config,
+        cookie,
+    };
 
     // Return a `Future` that is basically a state machine managing
     // this specific user's connection.
 
-    // Make an extra clone to give to our disconnection handler...
-    let users2 = users.clone();
-
     // Every time the user sends a message, broadcast it to
     // all other users...
-    while let Some(result) = user_ws_rx.next().await {
-        let msg = match result {
-            Ok(msg) => msg,
-            Err(e) => {
-                eprintln!("websocket error(uid={}): {}", my_id, e);
-                break;
+    //
+    // Meanwhile, ping the client: browsers answer on their own, so a client
+    // that stays silent is gone, even if TCP has not noticed yet.
+    let mut ping = tokio::time::interval_at(
+        tokio::time::Instant::now() + config.limits.ping_interval(),
+        config.limits.ping_interval(),
+    );
+    let mut last_seen = Instant::now();
+    loop {
+        tokio::select! {
+            result = user_ws_rx.next() => {
+                let msg = match result {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        warn!("websocket error(uid={}): {}", my_id, e);
+                        break;
+                    }
+                    None => break,
+                };
+                last_seen = Instant::now();
+                if msg.is_text() {
+                    conn.last_active = last_seen;
+                }
+                let _busy = app.gate.read().await;
+                user_message(&mut conn, msg, &app).await;
             }
-        };
-        user_id = user_message(my_id, user_id, msg, &users, &root).await;
+            _ = ping.tick() => {
+                if last_seen.elapsed() > config.limits.ping_timeout() {
+                    info!("user {} timed out", my_id);
+                    break;
+                }
+                if conn.last_active.elapsed() > config.limits.idle_timeout() && conn.credentials().is_ok() {
+                    sign_out_idle(&mut conn).await;
+                }
+                conn.tx.push(Kind::Durable, Message::ping(Vec::new()));
+            }
+        }
     }
 
     // user_ws_rx stream will keep processing as long as the user stays
     // connected. Once they disconnect, then...
-    user_disconnected(my_id, &users2).await;
+    user_disconnected(&conn).await;
 }
 
-async fn user_message(my_id: usize, user: [u8; 16], msg: Message, users: &Users, root: &RootPack) -> [u8; 16] {
+async fn user_message(conn: &mut Connection, msg: Message, app: &App) {
+    let App { root, registry, admins, .. } = app;
     // Skip any non-Text messages...
     let msg = if let Ok(s) = msg.to_str() {
         s
     } else {
-        return [0; 16];
+        return;
     };
 
-    if let Ok(v) = serde_json::from_str(msg) as Rslt<Value> {
-        let cmd = &v["type"];
-        if cmd == "login" || cmd == "new_user" {
-            // Save the sender's name in our list of connected users, if not already set.
-            let tx = &users.read().await[&my_id];
-            let tx = AssertTxInSafe(tx);
-            return P::transaction(|j| {
+    // Do not even parse what no valid command can be. The socket already
+    // refuses such messages; this only backs it up
+    let max_len = conn.config.limits.max_message_len;
+    if msg.len() > max_len {
+        penalize(
+            conn,
+            Invalid::new(
+                "too_large",
+                format!("messages must be at most {} bytes", max_len),
+            ),
+        );
+        return;
+    }
+
+    let my_id = conn.id;
+    let cmd = match validate::parse(msg) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            conn.reject(e);
+            return;
+        }
+    };
+    let class = Class::of(&cmd);
+    let user_allowed = conn.credentials().map_or(true, |c| conn.limits.allow(c.user, class));
+    if !conn.limiter.allow(class) || !user_allowed {
+        penalize(
+            conn,
+            Invalid::new(
+                "rate_limited",
+                format!("too many {} commands, slow down", class.name()),
+            ),
+        );
+        return;
+    }
+    if conn.spectator && !role::read_only(&cmd) {
+        conn.reject(Invalid::new("spectator", "spectators cannot change anything"));
+        return;
+    }
+
+    // Check the role on the board before anything that changes it
+    if conn.greeted {
+        if let Some(need) = role::required(&cmd) {
+            if let Err(e) = authorize(conn, root, admins, conn.room.id, need) {
+                return conn.reject(e);
+            }
+        }
+    }
+
+    let user = conn.user;
+    match cmd {
+        ClientMsg::Hello { version } => {
+            if version == PROTOCOL_VERSION {
+                conn.greeted = true;
+                conn.reply(ServerMsg::Hello { version: PROTOCOL_VERSION }.to_string());
+            } else {
+                info!("User<#{}> speaks protocol version {}!", my_id, version);
+                conn.reply(ServerMsg::UnsupportedVersion { version: PROTOCOL_VERSION }.to_string());
+                conn.tx.close();
+            }
+        }
+        _ if !conn.greeted => {
+            conn.reject(Invalid::new("no_hello", "say hello first"));
+        }
+        ClientMsg::Login { username, password } => {
+            login(conn, root, admins, &username, &password, false).await;
+        }
+        ClientMsg::NewUser { username, password } => {
+            login(conn, root, admins, &username, &password, true).await;
+        }
+        ClientMsg::Auth { session } => {
+            // Already checked by `validate::check`, unlike the cookie
+            let token = match session.as_deref().or(conn.cookie.as_deref()) {
+                Some(token) => match session::parse_token(token) {
+                    Some(token) => token,
+                    None => return conn.reject(bad_session()),
+                },
+                None => return conn.reject(not_signed_in()),
+            };
+            if let Err(e) = take_session(conn, root, admins, token).await {
+                conn.reject(e);
+            }
+        }
+        ClientMsg::Redeem { invite, guest } => {
+            let board = conn.room.id;
+            match P::transaction(|j| {
                 if let Some(root) = root.promote(j) {
                     let mut root = root.lock(j);
-                    let name = v["username"].as_str().unwrap();
-                    let pass = v["password"].as_str().unwrap();
-                    let password = *compute(pass);
-
-                    println!("received user: {}", name);
-                    println!("received pass: {:?}", pass);
-                    let user_id = *compute(name);
-                    if let Some(u) = root.data.get_ref(user_id) {
-                        if u.password == password {
-                            println!("Logged in");
-                            if let Err(disconnected) = tx.0.send(Ok(Message::text(format!(
-                                "{{\"type\": \"login\", \"user\": \"{}\", \"name\": \"{}\", \"color\": \"{}\"}}",
-                                user.encode_hex::<String>(), u.username, u.color
-                            )))) {
-                                eprintln!("User<#{}> is disconnected!", disconnected);
-                            }
-                            user_id
-                        } else {
-                            println!("Wrong password");
-                            if let Err(disconnected) = tx.0.send(Ok(Message::text(format!(
-                                "{{\"type\": \"wrong\"}}"
-                            )))) {
-                                eprintln!("User<#{}> is disconnected!", disconnected);
-                            }
-                            [0; 16]
-                        }
-                    } else if cmd == "new_user" {
-                        root.data.put(
-                            user_id,
-                            UserInfo {
-                                username: name.to_pstring(j),
-                                password,
-                                color: COLOR_PALLETE[(my_id - 1) % 8],
-                                history: RootObj::init(j),
-                            },
-                            j,
-                        );
-                        user_id
-                    } else {
-                        println!("User doesn't exist");
-                        if let Err(disconnected) = tx.0.send(Ok(Message::text(format!(
-                            "{{\"type\": \"not_exists\"}}"
-                        )))) {
-                            eprintln!("User<#{}> is disconnected!", disconnected);
-                        }
-                        [0; 16]
+                    let id = root.invites.verify(&invite).ok_or_else(bad_invite)?;
+                    // Do not use up invites to other boards
+                    let role = match root.invites.check(&id) {
+                        Some(i) if i.board == board => i.role,
+                        _ => return Err(bad_invite()),
+                    };
+                    // Guests coming back keep their id, and their lines
+                    let known = guest.as_deref().and_then(|token| root.invites.verify_guest(token));
+                    if let Some((_, user)) = known.filter(|(of, _)| *of == id) {
+                        return Ok((id, user, role, guest.clone().unwrap_or_default()));
                     }
+                    root.invites.redeem(&id, j).ok_or_else(bad_invite)?;
+                    let user = rand::random();
+                    Ok((id, user, role, root.invites.admit(&id, &user)))
                 } else {
-                    user
+                    Err(bad_invite())
+                }
+            }) {
+                Ok(Ok((id, user, role, guest))) => {
+                    conn.user = user;
+                    conn.session = None;
+                    conn.invite = Some(id);
+                    conn.reply(ServerMsg::Redeemed { guest }.to_string());
+                    conn.reply(ServerMsg::MyColor { data: conn.color }.to_string());
+                    conn.reply(ServerMsg::MyRole { role }.to_string());
+                    join(conn, guest_name(conn), conn.color).await;
+                }
+                Ok(Err(e)) => conn.reject(e),
+                Err(e) => conn.reject(internal(e)),
+            }
+        }
+        ClientMsg::Logout => {
+            if let Some(token) = conn.session.take() {
+                if let Err(e) = P::transaction(|j| {
+                    if let Some(root) = root.promote(j) {
+                        root.lock(j).sessions.close(&token, j);
+                    }
+                }) {
+                    conn.reject(internal(e));
+                }
+            }
+            conn.user = [0; 16];
+            conn.invite = None;
+            leave(conn).await;
+        }
+        ClientMsg::ChangePassword { old_password, new_password } => {
+            match change_password(conn, root, &old_password, &new_password).await {
+                Ok(()) => conn.reply(ServerMsg::PasswordChanged.to_string()),
+                Err(e) => conn.reject(e),
+            }
+        }
+        ClientMsg::Rename { name } => match rename(conn, root, &name) {
+            Ok(()) => {
+                if let Some(member) = conn.room.member(conn.id).await {
+                    join(conn, name.clone(), member.color).await;
                 }
-            }).unwrap();
-        } else if cmd == "set_user" {
-            let user = *compute(v["data"].as_str().unwrap());
-            let tx = &users.read().await[&my_id];
-            match P::transaction(AssertTxInSafe(|j| {
+                conn.reply(ServerMsg::Renamed { name }.to_string());
+            }
+            Err(e) => conn.reject(e),
+        },
+        ClientMsg::DeleteAccount { password } => {
+            match delete_account(conn, root, registry, &password).await {
+                Ok(()) => {
+                    conn.user = [0; 16];
+                    conn.session = None;
+                    leave(conn).await;
+                    conn.reply(ServerMsg::AccountDeleted.to_string());
+                }
+                Err(e) => conn.reject(e),
+            }
+        }
+        ClientMsg::Unlock { username } => match unlock(conn, root, admins, &username) {
+            Ok(()) => conn.reply(ServerMsg::Unlocked { username }.to_string()),
+            Err(e) => conn.reject(e),
+        },
+        ClientMsg::SetColor { data } => {
+            // Already checked by `validate::check`
+            let c = validate::color(&data).unwrap_or(0);
+            let token = match conn.session {
+                Some(token) => token,
+                None if conn.invite.is_some() => {
+                    conn.color = c;
+                    return recolor(conn, c).await;
+                }
+                None => return conn.reject(not_signed_in()),
+            };
+            match P::transaction(|j| {
                 if let Some(root) = root.promote(j) {
-                    if let Some(u) = root.lock(j).data.get_ref(user) {
-                        u.color
-                    } else { 0 }
-                } else { 0 }
-            })) {
-                Err(e) => eprintln!("Error: {}", e),
-                Ok(c) => {
-                    let _ = tx.send(Ok(Message::text(
-                        serde_json::to_string(&json!({
-                            "type": "my_color",
-                            "data": c
-                        })).unwrap())));
+                    let root = root.lock(j);
+                    match root.sessions.check(&token) {
+                        Some(user) => root.data.update_inplace_mut(&user, j, |w| w.color = c),
+                        None => false,
+                    }
+                } else {
+                    false
                 }
+            }) {
+                Ok(true) => recolor(conn, c).await,
+                Ok(false) => conn.reject(bad_session()),
+                Err(e) => conn.reject(internal(e)),
             }
-            return user;
-        } else if cmd == "set_color" {
-            if let Err(e) = P::transaction(|j| {
+        }
+        ClientMsg::Redraw | ClientMsg::Refresh => {
+            // Only the requesting client needs the whole board; the others
+            // are kept up to date by the change feed.
+            let msg = conn.room.snapshot.write().await.redraw();
+            conn.reply(msg);
+        }
+        ClientMsg::Resume { since_version } => {
+            let mut board = conn.room.snapshot.write().await;
+            let id = conn.room.id;
+            let missed = P::transaction(|j| {
                 if let Some(root) = root.promote(j) {
-                    let s = &v["data"].as_str().unwrap()[1..];
-                    let c = u32::from_str_radix(s, 16).unwrap();
-                    if !root.lock(j).data.update_inplace_mut(&user, j, |w| w.color = c) {
-                        eprintln!("User does not exist!");
+                    let root = root.lock(j);
+                    let missed = root.boards.get_ref(id).and_then(|b| b.oplog.since(since_version));
+                    missed
+                } else {
+                    None
+                }
+            }).unwrap_or(None);
+            // Fall back to the whole board if the log no longer covers the gap.
+            // The events go out as a single message, so that however many
+            // were missed, they take one place in the outbox
+            match missed {
+                Some(events) if events.is_empty() => {}
+                Some(events) => conn.reply(replay(events)),
+                None => conn.reply(board.redraw()),
+            }
+        }
+        ClientMsg::Undo => {
+            publish(conn, root, admins, |j, _, h| {
+                let last = h.last_timestamp(j);
+                if h.undo() {
+                    Change::Hidden((last, user))
+                } else {
+                    Change::None
+                }
+            })
+            .await;
+        }
+        ClientMsg::Redo => {
+            publish(conn, root, admins, |j, _, h| {
+                if h.redo() {
+                    if let Some(line) = h.current(j) {
+                        return Change::Restored((line.timestamp(), user), line.as_json());
+                    }
+                }
+                Change::None
+            })
+            .await;
+        }
+        ClientMsg::Clear => {
+            publish(conn, root, admins, |_, _, h| {
+                if h.clear() {
+                    Change::Cleared(user)
+                } else {
+                    Change::None
+                }
+            })
+            .await;
+        }
+        ClientMsg::Draw { data } => {
+            if !data.is_empty() {
+                let arr: Vec<(i32, i32)> = data.iter().map(|p| (p.x, p.y)).collect();
+                publish(conn, root, admins, |j, c, h| draw_line(j, h, user, &arr, c)).await;
+            }
+        }
+        ClientMsg::DrawTmp { color, data } => {
+            // New stroke segment from this user, sent to everyone on the next
+            // tick along with the others
+            let recipients = conn.room.clients.read().await.len();
+            Metrics::add(&METRICS.draw_tmp_received, 1);
+            Metrics::add(&METRICS.draw_tmp_unbatched, recipients as u64);
+            conn.room.draw_tmp(conn.id, color, data);
+        }
+        ClientMsg::ListBoards => {
+            let data = registry.read().await.names();
+            conn.reply(ServerMsg::Boards { data }.to_string());
+        }
+        ClientMsg::CreateBoard { name } => match create_board(conn, root, registry, &name).await {
+            Ok(()) => conn.reply(ServerMsg::BoardCreated { name }.to_string()),
+            Err(e) => conn.reject(e),
+        },
+        ClientMsg::RenameBoard { name, new_name } => {
+            match rename_board(conn, root, registry, admins, &name, &new_name).await {
+                Ok(room) => {
+                    let msg = ServerMsg::BoardRenamed { name, new_name }.to_string();
+                    room.broadcast(&msg).await;
+                    if !Arc::ptr_eq(&room, &conn.room) {
+                        conn.reply(msg);
                     }
                 }
+                Err(e) => conn.reject(e),
+            }
+        }
+        ClientMsg::DeleteBoard { name } => match delete_board(conn, root, registry, admins, &name).await {
+            Ok(room) => {
+                let msg = ServerMsg::BoardDeleted { name }.to_string();
+                room.broadcast(&msg).await;
+                room.close().await;
+                if !Arc::ptr_eq(&room, &conn.room) {
+                    conn.reply(msg);
+                }
+            }
+            Err(e) => conn.reject(e),
+        },
+        ClientMsg::Grant { board, username, role } => {
+            match set_role(conn, root, registry, admins, &board, &username, Some(role)).await {
+                Ok(()) => conn.reply(ServerMsg::Granted { board, username, role }.to_string()),
+                Err(e) => conn.reject(e),
+            }
+        }
+        ClientMsg::Revoke { board, username } => {
+            match set_role(conn, root, registry, admins, &board, &username, None).await {
+                Ok(()) => conn.reply(ServerMsg::Revoked { board, username }.to_string()),
+                Err(e) => conn.reject(e),
+            }
+        }
+        ClientMsg::CreateInvite { board, role, expires_in, max_uses } => {
+            let room = registry.read().await.get(&board);
+            match room
+                .ok_or_else(no_board)
+                .and_then(|room| create_invite(conn, root, admins, &room, role, expires_in, max_uses))
+            {
+                Ok((invite, expires)) => {
+                    conn.reply(ServerMsg::Invite { invite, board, role, expires }.to_string())
+                }
+                Err(e) => conn.reject(e),
+            }
+        }
+        ClientMsg::RevokeInvite { invite } => match revoke_invite(conn, root, admins, &invite) {
+            Ok(()) => conn.reply(ServerMsg::InviteRevoked.to_string()),
+            Err(e) => conn.reject(e),
+        },
+        ClientMsg::Who => {
+            let users = conn.room.members().await;
+            let (_, spectators) = conn.room.count().await;
+            conn.reply(ServerMsg::Who { users, spectators }.to_string());
+        }
+        ClientMsg::Cursor { x, y } => {
+            if let Err(e) = conn.credentials() {
+                return conn.reject(e);
+            }
+            let now = Instant::now();
+            if let Some(last) = conn.last_cursor {
+                if now.duration_since(last) < CURSOR_INTERVAL {
+                    return;
+                }
+            }
+            conn.last_cursor = Some(now);
+            let msg = ServerMsg::Cursor { id: conn.id, x, y }.to_string();
+            conn.room.relay(Some(conn.id), Kind::Latest(conn.id), &msg).await;
+        }
+        ClientMsg::Chat { text } => match post_chat(conn, root, admins, &text) {
+            Ok(entry) => conn.room.broadcast(&ServerMsg::Chat(entry).to_string()).await,
+            Err(e) => conn.reject(e),
+        },
+        ClientMsg::ChatHistory { before } => {
+            let board = conn.room.id;
+            match P::transaction(|j| {
+                if let Some(root) = root.promote(j) {
+                    let root = root.lock(j);
+                    let page = root.boards.get_ref(board).map(|b| b.chat.page(before));
+                    page
+                } else {
+                    None
+                }
             }) {
-                eprintln!("Error: {}", e);
+                Ok(Some((messages, more))) => {
+                    conn.reply(ServerMsg::ChatHistory { messages, more }.to_string())
+                }
+                Ok(None) => conn.reject(no_board()),
+                Err(e) => conn.reject(internal(e)),
+            }
+        }
+    }
+}
+
+/// The outcome of checking the credentials of a `login` or `new_user`
+enum Verdict {
+    /// The password matches; `rehash` replaces a legacy hash
+    Valid { rehash: Option<Hash> },
+    Wrong,
+    Missing,
+    /// `new_user` asked for a name that is taken
+    Exists,
+    /// The user does not exist yet and is to be created with this hash
+    Create(Hash),
+}
+
+/// Signs the connection in as an existing user, or registers a new one if
+/// `create` is set
+async fn login(conn: &mut Connection, root: &RootPack, admins: &Admins, name: &str, pass: &str, create: bool) {
+    let color = conn.config.color(conn.id - 1);
+
+    if let Some(wait) = conn.attempts.blocked() {
+        return conn.reject(too_many_attempts(wait));
+    }
+
+    match authenticate(root, name, pass, create, color).await {
+        Ok((msg, _, token)) => {
+            conn.attempts = Attempts::default();
+            conn.reply(msg.to_string());
+            if let Err(e) = take_session(conn, root, admins, token).await {
+                conn.reject(e);
             }
-        } else if cmd == "undo" || cmd == "redo" || cmd == "redraw" || cmd == "clear" || cmd == "refresh" {
-            let res = if cmd == "redraw" || cmd == "refresh" {
-                Ok(true)
+        }
+        Err(e) => {
+            conn.user = [0; 16];
+            conn.session = None;
+            conn.invite = None;
+            leave(conn).await;
+            if e.code == "wrong" || e.code == "not_exists" {
+                conn.attempts.fail();
+                conn.reply(e.login_reply().to_string());
             } else {
-                P::transaction(|j| {
-                    let mut done = false;
-                    if let Some(root) = root.promote(j) {
-                        if !root.lock(j).data.update_inplace(&user, |w| {
-                            done = if cmd == "clear" {
-                                w.history.clear()
-                            } else if cmd == "undo" {
-                                w.history.undo()
-                            } else {
-                                w.history.redo()
-                            };
-                        }) {
-                            eprintln!("User does not exist!");
-                        }
-                    }
-                    done
-                })
+                conn.reject(e);
+            }
+        }
+    }
+}
+
+/// Makes the connection act for the owner of a session from now on, as
+/// after `auth` or `login`: tells the client its color and role on the board,
+/// and shows it to the others under its new name
+async fn take_session(conn: &mut Connection, root: &RootPack, admins: &Admins, token: [u8; 16]) -> Result<(), Invalid> {
+    let board = conn.room.id;
+    let (user, name, color, role) = match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            let root = root.lock(j);
+            if let Some(user) = root.sessions.check(&token) {
+                if let Some(u) = root.data.get_ref(user) {
+                    let role = if admins.contains(&user) {
+                        Some(Role::Owner)
+                    } else {
+                        root.role(board, user)
+                    };
+                    let name = u.username.as_str().to_string();
+                    return Some((user, name, u.color, role));
+                }
+            }
+        }
+        None
+    }) {
+        Ok(Some(found)) => found,
+        Ok(None) => return Err(bad_session()),
+        Err(e) => return Err(internal(e)),
+    };
+    if conn.user != user {
+        leave(conn).await;
+    }
+    conn.user = user;
+    conn.session = Some(token);
+    conn.invite = None;
+    conn.reply(ServerMsg::MyColor { data: color }.to_string());
+    if let Some(role) = role {
+        conn.reply(ServerMsg::MyRole { role }.to_string());
+    }
+    join(conn, name, color).await;
+    Ok(())
+}
+
+/// Checks the password of `name`, or registers them with it and `color` if
+/// `create` is set, and opens a session. Returns the message to answer with,
+/// the user and the session token.
+async fn authenticate(
+    root: &RootPack,
+    name: &str,
+    pass: &str,
+    create: bool,
+    color: u32,
+) -> Result<(ServerMsg<'static>, [u8; 16], [u8; 16]), Invalid> {
+    let user_id = *compute(name);
+
+    // Hashing is slow by design, so look the hash up first, and check it
+    // outside of the transaction and off the async executor. The attempt
+    // counts as failed until the password proves right, so that attempts made
+    // in parallel cannot all get past the lockout before any of them fails.
+    let stored = match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            let mut root = root.lock(j);
+            if let Some(wait) = root.lockouts.blocked(user_id) {
+                return Err(wait);
+            }
+            let stored = root.data.get_ref(user_id).map(|u| u.password.load());
+            if stored.is_some() && !create {
+                root.lockouts.fail(user_id, j);
+            }
+            Ok(stored)
+        } else {
+            Ok(None)
+        }
+    }) {
+        Ok(Ok(stored)) => stored,
+        Ok(Err(wait)) => return Err(too_many_attempts(wait)),
+        Err(e) => return Err(internal(e)),
+    };
+    let pass = pass.to_string();
+    let verdict = tokio::task::spawn_blocking(move || match stored {
+        Some(_) if create => Ok(Verdict::Exists),
+        Some(hash) if hash.verify(&pass) => {
+            let rehash = if hash.is_legacy() {
+                Hash::new(&pass).ok()
+            } else {
+                None
             };
-            if let Ok(done) = res {
-                if done {
-                    if let Ok(msg) = P::transaction(|j| {
-                        let mut global_history = BTreeMap::<SystemTime, Value>::new();
-                        if let Some(root) = root.promote(j) {
-                            root.lock(j).data.foreach(|_, root| {
-                                let mut curr = root.history.head();
-                                let last = root.history.last_timestamp(j);
-                                while let Some(item) = curr.promote(j) {
-                                    if item.timestamp() <= last {
-                                        global_history.insert(item.timestamp(), item.as_json());
-                                    } else {
-                                        break;
-                                    }
-                                    curr = item.next();
-                                }
-                            });
-                        }
-                        let mut lst = vec![];
-                        for (_, item) in global_history {
-                            lst.push(item);
-                        }
-                        serde_json::to_string(&json!({
-                            "type": "redraw",
-                            "data": lst
-                        }))
-                        .unwrap()
-                    }) {
-                        let to_all = cmd != "refresh";
-                        for (&id, tx) in users.read().await.iter() {
-                            if to_all || id == my_id {
-                                if let Err(disconnected) = tx.send(Ok(Message::text(msg.clone()))) {
-                                    eprintln!("User<#{}> is disconnected!", disconnected);
-                                }
-                            }
-                        }
-                    }
+            Ok(Verdict::Valid { rehash })
+        }
+        Some(_) => Ok(Verdict::Wrong),
+        None if create => Hash::new(&pass).map(Verdict::Create),
+        None => Ok(Verdict::Missing),
+    })
+    .await;
+    let verdict = match verdict {
+        Ok(Ok(verdict)) => verdict,
+        Ok(Err(e)) => return Err(internal(e)),
+        Err(e) => return Err(internal(e)),
+    };
+
+    let res = P::transaction(|j| {
+        let root = match root.promote(j) {
+            Some(root) => root,
+            None => return Err(internal("the pool is closed")),
+        };
+        let mut root = root.lock(j);
+        match &verdict {
+            Verdict::Valid { rehash } => {
+                if let Some(hash) = rehash {
+                    info!("Upgrading the password hash of {}", name);
+                    root.set_password(user_id, hash, j);
                 }
-            } else if let Err(e) = res {
-                eprintln!("Error: {}", e);
+                root.lockouts.reset(user_id, j);
+                if let Some(u) = root.data.get_ref(user_id) {
+                    info!("{} logged in", name);
+                    let name = u.username.as_str().to_string();
+                    let color = u.color;
+                    let token = root.sessions.open(user_id, j);
+                    let msg = ServerMsg::Login {
+                        user: user_id.encode_hex::<String>(),
+                        name,
+                        color,
+                        session: token.encode_hex::<String>(),
+                    };
+                    return Ok((msg, user_id, token));
+                }
+                Err(Invalid::new("not_exists", "the user does not exist"))
+            }
+            Verdict::Create(hash) => {
+                // Someone else may have taken the name in the meantime
+                if !root.register(user_id, name, hash, color, j) {
+                    return Err(user_exists());
+                }
+                info!("Registered {}", name);
+                let token = root.sessions.open(user_id, j);
+                let msg = ServerMsg::Registered {
+                    user: user_id.encode_hex::<String>(),
+                    name: name.to_string(),
+                    color,
+                    session: token.encode_hex::<String>(),
+                };
+                Ok((msg, user_id, token))
+            }
+            Verdict::Exists => Err(user_exists()),
+            Verdict::Wrong => {
+                // Already counted before checking
+                info!("Wrong password for {}", name);
+                Err(Invalid::new("wrong", "wrong password"))
+            }
+            Verdict::Missing => {
+                info!("User {} doesn't exist", name);
+                Err(Invalid::new("not_exists", "the user does not exist"))
+            }
+        }
+    });
+    match res {
+        Ok(res) => res,
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Returns the signed-in user of the connection and their password hash
+fn stored_hash(conn: &Connection, root: &RootPack) -> Result<([u8; 16], Hash), Invalid> {
+    let token = conn.session.ok_or_else(not_signed_in)?;
+    match P::transaction(|j| {
+        let root = root.promote(j)?;
+        let root = root.lock(j);
+        let user = root.sessions.check(&token)?;
+        root.data.get_ref(user).map(|u| (user, u.password.load()))
+    }) {
+        Ok(Some(found)) => Ok(found),
+        Ok(None) => Err(bad_session()),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Checks the password of the signed-in user before a sensitive change
+async fn verify_password(conn: &Connection, root: &RootPack, pass: &str) -> Result<[u8; 16], Invalid> {
+    let (user, hash) = stored_hash(conn, root)?;
+    let pass = pass.to_string();
+    match tokio::task::spawn_blocking(move || hash.verify(&pass)).await {
+        Ok(true) => Ok(user),
+        Ok(false) => Err(Invalid::new("wrong_password", "wrong password")),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+async fn change_password(conn: &Connection, root: &RootPack, old: &str, new: &str) -> Result<(), Invalid> {
+    let user = verify_password(conn, root, old).await?;
+    let new = new.to_string();
+    let hash = match tokio::task::spawn_blocking(move || Hash::new(&new)).await {
+        Ok(Ok(hash)) => hash,
+        Ok(Err(e)) => return Err(internal(e)),
+        Err(e) => return Err(internal(e)),
+    };
+    let token = conn.session;
+    match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            let mut root = root.lock(j);
+            if root.set_password(user, &hash, j) {
+                // Sign out everywhere else
+                root.sessions.close_all(user, token, j);
+                return true;
+            }
+        }
+        false
+    }) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(bad_session()),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Lets an admin clear the failed logins of an account
+fn unlock(conn: &Connection, root: &RootPack, admins: &Admins, name: &str) -> Result<(), Invalid> {
+    let token = conn.session.ok_or_else(not_signed_in)?;
+    let target = *compute(name);
+    match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            let mut root = root.lock(j);
+            match root.sessions.check(&token) {
+                Some(user) if admins.contains(&user) => {
+                    root.lockouts.reset(target, j);
+                    Ok(())
+                }
+                Some(_) => Err(Invalid::new("forbidden", "only admins can unlock accounts")),
+                None => Err(bad_session()),
             }
         } else {
-            if cmd == "draw" {
-                if let Some(points) = v["data"].as_array() {
-                    if !points.is_empty() {
-                        let mut arr = Vec::<(i32, i32)>::with_capacity(points.len());
-                        for p in points {
-                            arr.push((
-                                p["x"].as_i64().unwrap() as i32,
-                                p["y"].as_i64().unwrap() as i32,
-                            ));
-                        }
-                        if let Err(e) = P::transaction(|j| {
-                            if let Some(root) = root.promote(j) {
-                                let root = root.lock(j);
-                                let c = if let Some(r) = root.data.get_ref(user) {
-                                    r.color
-                                } else {
-                                    0
-                                };
-                                if !root.data.update_inplace(&user, |w| w.history.add(j, &arr, c)) {
-                                    eprintln!("User does not exist!");
-                                }
-                            }
-                        }) {
-                            eprintln!("Error: {}", e);
-                        }
+            Err(bad_session())
+        }
+    }) {
+        Ok(res) => res,
+        Err(e) => Err(internal(e)),
+    }
+}
+
+fn rename(conn: &Connection, root: &RootPack, name: &str) -> Result<(), Invalid> {
+    let token = conn.session.ok_or_else(not_signed_in)?;
+    match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            let root = root.lock(j);
+            if let Some(user) = root.sessions.check(&token) {
+                return root.rename(user, name, j);
+            }
+        }
+        false
+    }) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(bad_session()),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Deletes the account of the signed-in user. Their lines disappear from
+/// every board like with `clear`, but the histories are freed for good.
+async fn delete_account(conn: &Connection, root: &RootPack, registry: &Registry, pass: &str) -> Result<(), Invalid> {
+    let user = verify_password(conn, root, pass).await?;
+    // Hold every board still, so that the account, its sessions and all its
+    // lines go in a single transaction, and the snapshots follow the pool
+    let registry = registry.read().await;
+    let rooms = registry.all();
+    let ids: Vec<[u8; 16]> = rooms.iter().map(|room| room.id).collect();
+    let mut boards = Vec::with_capacity(rooms.len());
+    for room in &rooms {
+        boards.push(room.snapshot.write().await);
+    }
+    let mut snapshots = AssertTxInSafe(&mut boards);
+    let events = match P::transaction(move |j| {
+        let root = root.promote(j).ok_or_else(bad_session)?;
+        let mut root = root.lock(j);
+        if !root.delete_user(user, j) {
+            return Err(bad_session());
+        }
+        let mut events = vec![];
+        for (id, snapshot) in ids.iter().zip(snapshots.0.iter_mut()) {
+            let mut cleared = vec![];
+            root.boards.update_inplace_mut(id, j, |b| {
+                b.roles.remove(&user, j);
+                if b.histories.remove(&user, j) {
+                    cleared = snapshot.apply(Change::Cleared(user));
+                    for event in &cleared {
+                        b.oplog.push(event, j);
                     }
                 }
+            });
+            events.push(cleared);
+        }
+        Ok(events)
+    }) {
+        Ok(res) => res?,
+        Err(e) => return Err(internal(e)),
+    };
+    drop(boards);
+    drop(registry);
+    for (room, events) in rooms.iter().zip(events) {
+        for event in events {
+            room.broadcast(&event).await;
+        }
+    }
+    Ok(())
+}
+
+/// Creates an empty board
+async fn create_board(conn: &Connection, root: &RootPack, registry: &Registry, name: &str) -> Result<(), Invalid> {
+    let token = conn.session.ok_or_else(not_signed_in)?;
+    let mut rooms = registry.write().await;
+    match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            let mut root = root.lock(j);
+            let user = match root.sessions.check(&token) {
+                Some(user) => user,
+                None => return Err(bad_session()),
+            };
+            // Only the creator may draw until they grant roles
+            root.create_board(name, Some(user), Role::Viewer, j)
+                .ok_or_else(board_exists)
+        } else {
+            Err(bad_session())
+        }
+    }) {
+        Ok(Ok(id)) => {
+            rooms.insert(name.to_string(), Arc::new(Room::new(id, Snapshot::default())));
+            Ok(())
+        }
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Renames a board and returns its room, so that its clients can be told
+async fn rename_board(
+    conn: &Connection,
+    root: &RootPack,
+    registry: &Registry,
+    admins: &Admins,
+    name: &str,
+    new_name: &str,
+) -> Result<Arc<Room>, Invalid> {
+    if name == DEFAULT_BOARD {
+        return Err(default_board());
+    }
+    let mut rooms = registry.write().await;
+    let room = rooms.get(name).ok_or_else(no_board)?;
+    authorize(conn, root, admins, room.id, Role::Owner)?;
+    match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            let root = root.lock(j);
+            if root.find_board(new_name).is_some() {
+                return Err(board_exists());
+            }
+            if root.rename_board(room.id, new_name, j) {
+                Ok(())
+            } else {
+                Err(no_board())
+            }
+        } else {
+            Err(bad_session())
+        }
+    }) {
+        Ok(Ok(())) => {
+            rooms.rename(name, new_name.to_string());
+            Ok(room)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Deletes a board and returns its room, so that its clients can be told
+/// and disconnected
+async fn delete_board(
+    conn: &Connection,
+    root: &RootPack,
+    registry: &Registry,
+    admins: &Admins,
+    name: &str,
+) -> Result<Arc<Room>, Invalid> {
+    if name == DEFAULT_BOARD {
+        return Err(default_board());
+    }
+    let mut rooms = registry.write().await;
+    let room = rooms.get(name).ok_or_else(no_board)?;
+    authorize(conn, root, admins, room.id, Role::Owner)?;
+    match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            let mut root = root.lock(j);
+            if root.delete_board(room.id, j) {
+                Ok(())
+            } else {
+                Err(no_board())
+            }
+        } else {
+            Err(bad_session())
+        }
+    }) {
+        Ok(Ok(())) => {
+            rooms.remove(name);
+            Ok(room)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Grants a role on a board to a user, or revokes it with `None`
+async fn set_role(
+    conn: &Connection,
+    root: &RootPack,
+    registry: &Registry,
+    admins: &Admins,
+    board: &str,
+    name: &str,
+    role: Option<Role>,
+) -> Result<(), Invalid> {
+    let room = registry.read().await.get(board).ok_or_else(no_board)?;
+    let owner = authorize(conn, root, admins, room.id, Role::Owner)?;
+    let target = *compute(name);
+    if target == owner {
+        return Err(Invalid::new("own_role", "you cannot change your own role"));
+    }
+    match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            let root = root.lock(j);
+            if root.data.get_ref(target).is_none() {
+                return Err(Invalid::new("not_exists", "the user does not exist"));
             }
+            if root.set_role(room.id, target, role, j) {
+                Ok(())
+            } else {
+                Err(no_board())
+            }
+        } else {
+            Err(bad_session())
+        }
+    }) {
+        Ok(res) => res,
+        Err(e) => Err(internal(e)),
+    }
+}
 
-            // New message from this user, send it to everyone else (except same uid)...
-            for (_, tx) in users.read().await.iter() {
-                if let Err(disconnected) = tx.send(Ok(Message::text(msg.clone()))) {
-                    // The tx is disconnected, our `user_disconnected` code
-                    // should be happening in another task, nothing more to
-                    // do here.
-                    eprintln!("User<#{}> is disconnected!", disconnected);
+/// Stores a chat message on the connection's board, signed with the current
+/// name of its author
+fn post_chat(conn: &Connection, root: &RootPack, admins: &Admins, text: &str) -> Result<ChatEntry, Invalid> {
+    let creds = conn.credentials()?;
+    let guest = guest_name(conn);
+    let board = conn.room.id;
+    match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            let root = root.lock(j);
+            let (user, _) = identify(&root, admins, board, creds)?;
+            let name = root
+                .data
+                .get_ref(user)
+                .map_or(guest, |u| u.username.as_str().to_string());
+            let mut entry = None;
+            root.boards.update_inplace_mut(&board, j, |b| {
+                entry = Some(b.chat.post(user, &name, text, j));
+            });
+            entry.ok_or_else(no_board)
+        } else {
+            Err(bad_session())
+        }
+    }) {
+        Ok(res) => res,
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Guests have no account, so they are named after their connection
+fn guest_name(conn: &Connection) -> String {
+    format!("guest-{}", conn.id)
+}
+
+/// Mints an invite to a board, for its owners only. Returns the token and
+/// when it expires.
+fn create_invite(
+    conn: &Connection,
+    root: &RootPack,
+    admins: &Admins,
+    room: &Room,
+    role: Role,
+    expires_in: u64,
+    max_uses: u32,
+) -> Result<(String, u64), Invalid> {
+    authorize(conn, root, admins, room.id, Role::Owner)?;
+    let invite = Invite {
+        board: room.id,
+        role,
+        expires: session::now() + expires_in,
+        uses_left: max_uses,
+    };
+    match P::transaction(|j| root.promote(j).map(|root| root.lock(j).invites.create(invite, j))) {
+        Ok(Some(token)) => Ok((token, invite.expires)),
+        Ok(None) => Err(bad_session()),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Revokes an invite, for the owners of its board only. Guests who joined
+/// with it lose their access.
+fn revoke_invite(conn: &Connection, root: &RootPack, admins: &Admins, token: &str) -> Result<(), Invalid> {
+    let (id, board) = match P::transaction(|j| {
+        let root = root.promote(j)?;
+        let root = root.lock(j);
+        let id = root.invites.verify(token)?;
+        root.invites.check(&id).map(|i| (id, i.board))
+    }) {
+        Ok(Some(found)) => found,
+        Ok(None) => return Err(bad_invite()),
+        Err(e) => return Err(internal(e)),
+    };
+    authorize(conn, root, admins, board, Role::Owner)?;
+    match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            root.lock(j).invites.revoke(&id, j)
+        } else {
+            false
+        }
+    }) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(bad_invite()),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Returns the user behind the credentials and their role on the board.
+/// Admins own every board.
+fn identify(root: &Database, admins: &Admins, board: [u8; 16], creds: Credentials) -> Result<([u8; 16], Role), Invalid> {
+    if let Some(token) = creds.session {
+        let user = root.sessions.check(&token).ok_or_else(bad_session)?;
+        let role = if admins.contains(&user) {
+            Role::Owner
+        } else {
+            root.role(board, user).ok_or_else(no_board)?
+        };
+        Ok((user, role))
+    } else if let Some(invite) = creds.invite {
+        match root.invites.check(&invite) {
+            Some(i) if i.board == board => Ok((creds.user, i.role)),
+            _ => Err(bad_invite()),
+        }
+    } else {
+        Err(not_signed_in())
+    }
+}
+
+/// Checks that the user or guest has at least the `need` role on the board,
+/// and returns them
+fn authorize(conn: &Connection, root: &RootPack, admins: &Admins, board: [u8; 16], need: Role) -> Result<[u8; 16], Invalid> {
+    allowed(root, admins, board, conn.credentials()?, need)
+}
+
+/// Checks that the credentials give at least the `need` role on the board,
+/// and returns who they belong to
+fn allowed(root: &RootPack, admins: &Admins, board: [u8; 16], creds: Credentials, need: Role) -> Result<[u8; 16], Invalid> {
+    match P::transaction(|j| {
+        if let Some(root) = root.promote(j) {
+            let root = root.lock(j);
+            let (user, role) = identify(&root, admins, board, creds)?;
+            if role >= need {
+                Ok(user)
+            } else {
+                Err(forbidden(need))
+            }
+        } else {
+            Err(bad_session())
+        }
+    }) {
+        Ok(res) => res,
+        Err(e) => Err(internal(e)),
+    }
+}
+
+fn internal<E: std::fmt::Display>(e: E) -> Invalid {
+    Invalid::new("internal", e.to_string())
+}
+
+fn not_signed_in() -> Invalid {
+    Invalid::new("not_signed_in", "sign in before drawing")
+}
+
+fn too_many_attempts(wait: u64) -> Invalid {
+    Invalid::new(
+        "too_many_attempts",
+        format!("too many failed logins, try again in {} seconds", wait),
+    )
+}
+
+fn user_exists() -> Invalid {
+    Invalid::new("user_exists", "the username is already taken")
+}
+
+fn bad_session() -> Invalid {
+    Invalid::new("bad_session", "the session is invalid or has expired")
+}
+
+fn bad_invite() -> Invalid {
+    Invalid::new("bad_invite", "the invite is invalid, revoked, expired or used up")
+}
+
+fn forbidden(need: Role) -> Invalid {
+    Invalid::new("forbidden", format!("this needs the {} role on the board", need))
+}
+
+fn no_board() -> Invalid {
+    Invalid::new("no_board", "the board does not exist")
+}
+
+fn board_exists() -> Invalid {
+    Invalid::new("board_exists", "the board name is already taken")
+}
+
+fn default_board() -> Invalid {
+    Invalid::new("default_board", "the default board cannot be renamed or deleted")
+}
+
+/// Runs `f` on the history of the user or guest on the connection's board,
+/// with the color they draw with, and publishes the resulting change.
+///
+/// The snapshot stays locked from the transaction until the end of the
+/// broadcast, so that the events are versioned, logged and delivered to every
+/// client in the same order. The events are logged in the same transaction
+/// that changes the history.
+async fn publish<F>(conn: &Connection, root: &RootPack, admins: &Admins, f: F)
+where
+    F: FnOnce(&Journal, u32, &History) -> Change,
+{
+    let creds = match conn.credentials() {
+        Ok(creds) => creds,
+        Err(e) => return conn.reject(e),
+    };
+    if let Err(e) = apply(&conn.room, root, admins, creds, conn.color, f).await {
+        conn.reject(e);
+    }
+}
+
+/// Applies a change to the history of the user on the board of `room`, and
+/// broadcasts the events it produced. Guests draw in `guest_color`.
+async fn apply<F>(
+    room: &Room,
+    root: &RootPack,
+    admins: &Admins,
+    creds: Credentials,
+    guest_color: u32,
+    f: F,
+) -> Result<Vec<String>, Invalid>
+where
+    F: FnOnce(&Journal, u32, &History) -> Change,
+{
+    let id = room.id;
+    let mut board = room.snapshot.write().await;
+    let mut snapshot = AssertTxInSafe(&mut *board);
+    let f = AssertTxInSafe(f);
+    match P::transaction(move |j| {
+        if let Some(root) = root.promote(j) {
+            let root = root.lock(j);
+            let (user, _) = identify(&root, admins, id, creds)?;
+            let color = root.data.get_ref(user).map_or(guest_color, |u| u.color);
+            let mut events = vec![];
+            let found = root.boards.update_inplace_mut(&id, j, |b| {
+                // The user draws on this board for the first time
+                if b.histories.get_ref(user).is_none() {
+                    b.histories.put(user, RootObj::init(j), j);
+                }
+                if let Some(history) = b.histories.get_ref(user) {
+                    events = snapshot.0.apply((f.0)(j, color, history));
+                }
+                for event in &events {
+                    b.oplog.push(event, j);
                 }
+            });
+            if found {
+                Ok(events)
+            } else {
+                Err(no_board())
+            }
+        } else {
+            Ok(vec![])
+        }
+    }) {
+        Ok(Ok(events)) => {
+            for event in &events {
+                room.broadcast(event).await;
             }
+            Ok(events)
         }
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(internal(e)),
+    }
+}
+
+/// Adds a line to the history of `user`
+fn draw_line(j: &Journal, history: &History, user: [u8; 16], points: &[(i32, i32)], color: u32) -> Change {
+    history.add(j, points, color);
+    if let Some(line) = history.current(j) {
+        Change::Added((line.timestamp(), user), line.as_json())
     } else {
-        eprintln!("received data is not a json object!");
+        Change::None
+    }
+}
+
+async fn user_disconnected(conn: &Connection) {
+    info!(
+        "good bye user: {} ({} live updates dropped)",
+        conn.id,
+        conn.tx.dropped()
+    );
+    conn.tx.close();
+
+    // Stream closed up, so remove from the user list of the board
+    leave(conn).await;
+    conn.room.clients.write().await.remove(&conn.id);
+    announce_presence(&conn.room).await;
+}
+
+/// Takes the rights of a connection that has been idle for too long. The
+/// session itself stays open, so that the client can sign in again.
+async fn sign_out_idle(conn: &mut Connection) {
+    info!("user {} is idle, signing them out", conn.id);
+    conn.reject(Invalid::new("idle", "signed out after a while without activity"));
+    conn.session = None;
+    conn.invite = None;
+    conn.user = [0; 16];
+    leave(conn).await;
+}
+
+/// Rejects a request over the limits, and kicks clients that keep sending them
+fn penalize(conn: &mut Connection, e: Invalid) {
+    conn.reject(e);
+    if !conn.strikes.take(STRIKES) {
+        conn.reject(Invalid::new("kicked", "too many requests over the limits"));
+        conn.tx.close();
+    }
+}
+
+/// Shows the connection to everyone on the board as `name`, in `color`.
+/// Spectators stay anonymous.
+async fn join(conn: &Connection, name: String, color: u32) {
+    if conn.spectator {
+        return;
+    }
+    let member = Member {
+        id: conn.id,
+        name,
+        color,
+    };
+    conn.room.join(conn.id, member.clone()).await;
+    conn.room.broadcast(&ServerMsg::UserJoined(member).to_string()).await;
+}
+
+/// Shows the new color of the connection to everyone on the board
+async fn recolor(conn: &Connection, color: u32) {
+    if let Some(member) = conn.room.member(conn.id).await {
+        join(conn, member.name, color).await;
     }
-    user
 }
 
-async fn user_disconnected(my_id: usize, users: &Users) {
-    eprintln!("good bye user: {}", my_id);
+async fn leave(conn: &Connection) {
+    if conn.room.leave(conn.id).await {
+        conn.room.broadcast(&ServerMsg::UserLeft { id: conn.id }.to_string()).await;
+    }
+}
 
-    // Stream closed up, so remove from the user list
-    users.write().await.remove(&my_id);
+/// Tells everyone on the board how many are watching it
+async fn announce_presence(room: &Room) {
+    let (clients, spectators) = room.count().await;
+    room.broadcast(&ServerMsg::Presence { clients, spectators }.to_string()).await;
 }
@@ -0,0 +1,42 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters about the live drawing updates, served at `/metrics`
+pub struct Metrics {
+    /// `draw_tmp` messages received from the clients
+    pub draw_tmp_received: AtomicU64,
+    /// Messages a fan-out of every `draw_tmp` to every client would have sent
+    pub draw_tmp_unbatched: AtomicU64,
+    /// Batched frames actually sent
+    pub frames_sent: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics {
+    draw_tmp_received: AtomicU64::new(0),
+    draw_tmp_unbatched: AtomicU64::new(0),
+    frames_sent: AtomicU64::new(0),
+};
+
+impl Metrics {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// The counters in the Prometheus text format
+    pub fn report(&self) -> String {
+        let received = self.draw_tmp_received.load(Ordering::Relaxed);
+        let unbatched = self.draw_tmp_unbatched.load(Ordering::Relaxed);
+        let sent = self.frames_sent.load(Ordering::Relaxed);
+        let reduction = if unbatched > 0 {
+            1.0 - sent as f64 / unbatched as f64
+        } else {
+            0.0
+        };
+        let mut s = String::new();
+        let _ = writeln!(s, "wb_draw_tmp_received_total {}", received);
+        let _ = writeln!(s, "wb_draw_tmp_unbatched_total {}", unbatched);
+        let _ = writeln!(s, "wb_draw_tmp_frames_sent_total {}", sent);
+        let _ = writeln!(s, "wb_draw_tmp_reduction_ratio {:.3}", reduction);
+        s
+    }
+}
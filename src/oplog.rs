@@ -0,0 +1,130 @@
+use crate::protocol::ServerMsg;
+use corundum::default::*;
+use serde_json::value::RawValue;
+
+type P = BuddyAlloc;
+
+/// The number of most recent events that can be replayed to a reconnecting
+/// client. Older gaps are filled with a full snapshot instead.
+pub const CAPACITY: usize = 1024;
+
+/// A bounded, persistent log of the board events.
+///
+/// The events are kept serialized in a ring buffer, where the event of
+/// version `v` lives at `(v - 1) % CAPACITY`. The version survives restarts,
+/// so that clients can resume across them.
+pub struct OpLog {
+    version: PCell<u64>,
+    entries: PVec<PRefCell<PString>>,
+}
+
+impl RootObj<P> for OpLog {
+    fn init(_j: &Journal) -> Self {
+        Self {
+            version: PCell::new(0),
+            entries: PVec::new(),
+        }
+    }
+}
+
+impl OpLog {
+    /// The version of the last logged event
+    pub fn version(&self) -> u64 {
+        self.version.get()
+    }
+
+    /// Appends the next event to the log, overwriting the oldest one if the
+    /// log is full
+    pub fn push(&mut self, event: &str, j: &Journal) {
+        let version = self.version.get() + 1;
+        let index = slot(version);
+        if index < self.entries.len() {
+            *self.entries[index].borrow_mut(j) = event.to_pstring(j);
+        } else {
+            self.entries.push(PRefCell::new(event.to_pstring(j)), j);
+        }
+        self.version.set(version, j);
+    }
+
+    /// Returns the events logged after version `since`, or `None` if some of
+    /// them are no longer in the log
+    pub fn since(&self, since: u64) -> Option<Vec<String>> {
+        let slots = slots(self.version.get(), self.entries.len(), since)?;
+        Some(slots.map(|index| self.entries[index].borrow().as_str().to_string()).collect())
+    }
+}
+
+/// Packs the events returned by `since` into a single `replay` message
+pub fn replay(events: Vec<String>) -> String {
+    let events = events
+        .into_iter()
+        .filter_map(|e| RawValue::from_string(e).ok())
+        .collect();
+    ServerMsg::Replay { events }.to_string()
+}
+
+/// Where the event of `version` lives in the ring
+fn slot(version: u64) -> usize {
+    ((version - 1) as usize) % CAPACITY
+}
+
+/// The slots of the events logged after version `since`, in order, in a log
+/// at `version` that holds `len` events, or `None` if some of them were
+/// overwritten or are yet to come
+fn slots(version: u64, len: usize, since: u64) -> Option<impl Iterator<Item = usize>> {
+    let oldest = version + 1 - len as u64;
+    if since > version || since + 1 < oldest {
+        return None;
+    }
+    Some((since + 1..=version).map(slot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resume(version: u64, len: usize, since: u64) -> Option<Vec<usize>> {
+        slots(version, len, since).map(|slots| slots.collect())
+    }
+
+    #[test]
+    fn replays_keep_the_events_in_order() {
+        let events = vec![r#"{"version":1}"#.to_string(), r#"{"version":2}"#.to_string()];
+        assert_eq!(replay(events), r#"{"type":"replay","events":[{"version":1},{"version":2}]}"#);
+    }
+
+    #[test]
+    fn an_empty_log_has_nothing_to_replay() {
+        assert_eq!(resume(0, 0, 0), Some(vec![]));
+        assert_eq!(resume(0, 0, 1), None);
+    }
+
+    #[test]
+    fn a_client_up_to_date_gets_nothing() {
+        assert_eq!(resume(3, 3, 3), Some(vec![]));
+    }
+
+    #[test]
+    fn a_client_ahead_of_the_log_gets_a_snapshot() {
+        // e.g. after the pool was replaced
+        assert_eq!(resume(3, 3, 4), None);
+    }
+
+    #[test]
+    fn a_client_behind_gets_what_it_missed() {
+        assert_eq!(resume(3, 3, 0), Some(vec![0, 1, 2]));
+        assert_eq!(resume(3, 3, 1), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn a_wrapped_ring_replays_across_its_end() {
+        let version = CAPACITY as u64 + 5;
+        let all = resume(version, CAPACITY, 5).unwrap();
+        assert_eq!(all.len(), CAPACITY);
+        assert_eq!(all[0], 5);
+        assert_eq!(all[CAPACITY - 6], CAPACITY - 1);
+        assert_eq!(all[CAPACITY - 5..], [0, 1, 2, 3, 4]);
+        assert_eq!(resume(version, CAPACITY, version - 2), Some(vec![3, 4]));
+        assert_eq!(resume(version, CAPACITY, 4), None);
+    }
+}
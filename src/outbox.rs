@@ -0,0 +1,221 @@
+use crate::protocol::ServerMsg;
+use futures::stream::SplitSink;
+use futures::SinkExt;
+use log::warn;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use warp::ws::{Message, WebSocket};
+
+/// The most messages queued for a single client
+pub const CAPACITY: usize = 256;
+
+/// How a message is treated when the client cannot keep up
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    /// Delivered in order, or not at all if the client is disconnected
+    Durable,
+    /// Only the latest one with the same key matters, such as the cursor of
+    /// a given client; it replaces the one still queued
+    Latest(usize),
+    /// Dropped if the queue is full, such as live stroke segments
+    Droppable,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<(Kind, Message)>,
+    /// No message is queued anymore once the closing one is
+    closed: bool,
+    /// Ephemeral messages dropped or replaced so far
+    dropped: u64,
+}
+
+/// The bounded queue of messages waiting to be sent to a client.
+///
+/// When it is full, ephemeral messages make room for durable ones. A client
+/// whose queue is full of durable messages is too far behind to catch up: it
+/// is told to resync and disconnected, so that it reconnects and resumes.
+#[derive(Default)]
+pub struct Outbox {
+    queue: Mutex<Queue>,
+    ready: Notify,
+}
+
+impl Outbox {
+    pub fn push(&self, kind: Kind, msg: Message) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return;
+        }
+        if let Kind::Latest(_) = kind {
+            if let Some(queued) = queue.messages.iter_mut().find(|(k, _)| *k == kind) {
+                queued.1 = msg;
+                queue.dropped += 1;
+                return;
+            }
+        }
+        if queue.messages.len() >= CAPACITY {
+            if kind != Kind::Durable {
+                queue.dropped += 1;
+                return;
+            }
+            match queue.messages.iter().position(|(k, _)| *k != Kind::Durable) {
+                Some(i) => {
+                    queue.messages.remove(i);
+                    queue.dropped += 1;
+                }
+                None => {
+                    warn!("A client is too far behind, disconnecting it");
+                    queue.messages.clear();
+                    let e = ServerMsg::Error {
+                        code: "lagging",
+                        message: "too far behind; reconnect to resync".to_string(),
+                    };
+                    queue.messages.push_back((kind, Message::text(e.to_string())));
+                    queue.messages.push_back((kind, Message::close()));
+                    queue.closed = true;
+                    self.ready.notify();
+                    return;
+                }
+            }
+        }
+        queue.messages.push_back((kind, msg));
+        self.ready.notify();
+    }
+
+    /// Sends the messages queued so far, then closes the connection
+    pub fn close(&self) {
+        self.push(Kind::Durable, Message::close());
+        self.queue.lock().unwrap().closed = true;
+    }
+
+    /// How many ephemeral messages were dropped or replaced
+    pub fn dropped(&self) -> u64 {
+        self.queue.lock().unwrap().dropped
+    }
+
+    /// Feeds the queued messages to the socket, until the outbox is closed or
+    /// the socket fails
+    pub async fn run(&self, mut sink: SplitSink<WebSocket, Message>) {
+        loop {
+            let next = {
+                let mut queue = self.queue.lock().unwrap();
+                match queue.messages.pop_front() {
+                    Some((_, msg)) => Some(msg),
+                    None if queue.closed => return,
+                    None => None,
+                }
+            };
+            match next {
+                Some(msg) => {
+                    let closing = msg.is_close();
+                    if let Err(e) = sink.send(msg).await {
+                        warn!("websocket send error: {}", e);
+                        return;
+                    }
+                    if closing {
+                        return;
+                    }
+                }
+                // `notify` keeps a permit if nobody waits yet, so a message
+                // queued in between is not missed
+                None => self.ready.notified().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oplog::{self, replay};
+
+    fn text(i: usize) -> Message {
+        Message::text(i.to_string())
+    }
+
+    /// The queued messages, with closing ones as `close`
+    fn queued(outbox: &Outbox) -> Vec<(Kind, String)> {
+        let queue = outbox.queue.lock().unwrap();
+        let text = |msg: &Message| match msg.to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) if msg.is_close() => "close".to_string(),
+            Err(_) => panic!("unexpected message {:?}", msg),
+        };
+        queue.messages.iter().map(|(k, msg)| (*k, text(msg))).collect()
+    }
+
+    #[test]
+    fn durable_messages_evict_the_oldest_droppable_one() {
+        let outbox = Outbox::default();
+        outbox.push(Kind::Durable, text(0));
+        outbox.push(Kind::Droppable, text(1));
+        outbox.push(Kind::Droppable, text(2));
+        for i in 3..CAPACITY {
+            outbox.push(Kind::Durable, text(i));
+        }
+        // Full: the droppable messages give way, and new ones are dropped
+        outbox.push(Kind::Droppable, text(CAPACITY));
+        outbox.push(Kind::Durable, text(CAPACITY + 1));
+        let messages = queued(&outbox);
+        assert_eq!(messages.len(), CAPACITY);
+        assert_eq!(messages[0].1, "0");
+        assert_eq!(messages[1], (Kind::Droppable, "2".to_string()));
+        assert_eq!(messages[CAPACITY - 1], (Kind::Durable, (CAPACITY + 1).to_string()));
+        assert_eq!(outbox.dropped(), 2);
+    }
+
+    #[test]
+    fn latest_messages_replace_the_queued_one_with_the_same_key() {
+        let outbox = Outbox::default();
+        outbox.push(Kind::Latest(1), text(0));
+        outbox.push(Kind::Latest(2), text(1));
+        outbox.push(Kind::Durable, text(2));
+        outbox.push(Kind::Latest(1), text(3));
+        assert_eq!(
+            queued(&outbox),
+            vec![
+                (Kind::Latest(1), "3".to_string()),
+                (Kind::Latest(2), "1".to_string()),
+                (Kind::Durable, "2".to_string()),
+            ]
+        );
+        assert_eq!(outbox.dropped(), 1);
+    }
+
+    #[test]
+    fn a_client_too_far_behind_is_told_and_closed() {
+        let outbox = Outbox::default();
+        for i in 0..=CAPACITY {
+            outbox.push(Kind::Durable, text(i));
+        }
+        let messages = queued(&outbox);
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].1.contains(r#""code":"lagging""#));
+        assert_eq!(messages[1].1, "close");
+
+        // Nothing gets in after the closing message
+        outbox.push(Kind::Durable, text(0));
+        outbox.push(Kind::Droppable, text(1));
+        outbox.close();
+        assert_eq!(queued(&outbox), messages);
+    }
+
+    #[test]
+    fn a_client_that_missed_the_whole_log_can_resume() {
+        // More events than the outbox holds messages
+        assert!(oplog::CAPACITY > CAPACITY);
+        let events = (1..=oplog::CAPACITY as u64)
+            .map(|v| format!(r#"{{"type":"line_hidden","version":{},"id":"x"}}"#, v))
+            .collect();
+        let outbox = Outbox::default();
+        outbox.push(Kind::Durable, Message::text(replay(events)));
+        for i in 0..CAPACITY - 1 {
+            outbox.push(Kind::Durable, Message::text(i.to_string()));
+        }
+        let queue = outbox.queue.lock().unwrap();
+        assert!(!queue.closed);
+        assert_eq!(queue.messages.len(), CAPACITY);
+    }
+}
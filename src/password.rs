@@ -0,0 +1,97 @@
+use argon2::password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use corundum::default::*;
+use hex::ToHex;
+
+/// The algorithm a stored password hash was made with
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Algo {
+    /// The unsalted MD5 digest of the password, in hex. Only found in
+    /// accounts created before salted hashes were introduced; they are
+    /// rehashed on their next successful login.
+    Md5,
+    /// An Argon2id hash with a random salt, as a PHC string
+    Argon2id,
+}
+
+/// A password hash as read from the pool.
+///
+/// Hashing and verifying are deliberately slow, so they happen on this
+/// volatile copy outside of any transaction.
+pub struct Hash {
+    algo: Algo,
+    hash: String,
+}
+
+impl Hash {
+    /// Hashes a new password with Argon2id and a random salt
+    pub fn new(pass: &str) -> Result<Hash, password_hash::Error> {
+        let salt: [u8; 16] = rand::random();
+        let salt = SaltString::b64_encode(&salt)?;
+        let hash = Argon2::default().hash_password(pass.as_bytes(), &salt)?;
+        Ok(Hash {
+            algo: Algo::Argon2id,
+            hash: hash.to_string(),
+        })
+    }
+
+    pub fn verify(&self, pass: &str) -> bool {
+        match self.algo {
+            Algo::Md5 => format!("{:x}", md5::compute(pass)) == self.hash,
+            Algo::Argon2id => match PasswordHash::new(&self.hash) {
+                Ok(hash) => Argon2::default().verify_password(pass.as_bytes(), &hash).is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+
+    /// Whether the hash should be replaced with one made by the current
+    /// algorithm
+    pub fn is_legacy(&self) -> bool {
+        self.algo != Algo::Argon2id
+    }
+}
+
+/// A password hash as stored in the pool, tagged with its algorithm
+pub struct Password {
+    algo: Algo,
+    hash: PString,
+}
+
+impl Default for Password {
+    fn default() -> Self {
+        Password {
+            algo: Algo::Md5,
+            hash: Default::default(),
+        }
+    }
+}
+
+impl Password {
+    pub fn new(hash: &Hash, j: &Journal) -> Self {
+        Password {
+            algo: hash.algo,
+            hash: hash.hash.to_pstring(j),
+        }
+    }
+
+    /// The hash of an account created before salted hashes, from the MD5
+    /// digest it was stored as
+    pub fn legacy(digest: &[u8; 16], j: &Journal) -> Self {
+        Password {
+            algo: Algo::Md5,
+            hash: digest.encode_hex::<String>().to_pstring(j),
+        }
+    }
+
+    pub fn load(&self) -> Hash {
+        Hash {
+            algo: self.algo,
+            hash: self.hash.as_str().to_string(),
+        }
+    }
+
+    pub fn algo(&self) -> Algo {
+        self.algo
+    }
+}
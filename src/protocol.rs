@@ -0,0 +1,150 @@
+use crate::role::Role;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::fmt;
+
+/// The version of the protocol spoken by this server. Clients announce
+/// theirs with `hello` before anything else, and are turned away if it does
+/// not match.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+
+/// The live stroke segments of a client during a tick
+#[derive(Serialize, Debug)]
+pub struct Stroke {
+    /// The id of the connection that drew them
+    pub author: usize,
+    pub color: u32,
+    pub segments: Vec<Vec<Point>>,
+}
+
+/// A client shown to the others on a board
+#[derive(Serialize, Clone, Debug)]
+pub struct Member {
+    /// The id of the connection, as a user may have several
+    pub id: usize,
+    pub name: String,
+    pub color: u32,
+}
+
+/// A chat message as sent to the clients
+#[derive(Serialize, Debug)]
+pub struct ChatEntry {
+    pub id: u64,
+    /// The id of the author, in hex
+    pub author: String,
+    pub name: String,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub text: String,
+}
+
+/// Messages sent by the clients
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMsg {
+    Hello { version: u32 },
+    Login { username: String, password: String },
+    NewUser { username: String, password: String },
+    /// Without a session, the one in the cookie set by the login page is used
+    Auth {
+        #[serde(default)]
+        session: Option<String>,
+    },
+    /// With the `guest` token handed out on the first redeem, the guest comes
+    /// back under the same id, and the invite is not used up again
+    Redeem {
+        invite: String,
+        #[serde(default)]
+        guest: Option<String>,
+    },
+    Logout,
+    ChangePassword { old_password: String, new_password: String },
+    Rename { name: String },
+    DeleteAccount { password: String },
+    Unlock { username: String },
+    SetColor { data: String },
+    Draw { data: Vec<Point> },
+    DrawTmp { color: u32, data: Vec<Point> },
+    Undo,
+    Redo,
+    Clear,
+    Redraw,
+    Refresh,
+    Resume { since_version: u64 },
+    ListBoards,
+    CreateBoard { name: String },
+    RenameBoard { name: String, new_name: String },
+    DeleteBoard { name: String },
+    Grant { board: String, username: String, role: Role },
+    Revoke { board: String, username: String },
+    CreateInvite { board: String, role: Role, expires_in: u64, max_uses: u32 },
+    RevokeInvite { invite: String },
+    Who,
+    Cursor { x: i32, y: i32 },
+    Chat { text: String },
+    /// Asks for older messages than `before`, or for the latest ones
+    ChatHistory { before: Option<u64> },
+}
+
+/// Messages sent by the server
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMsg<'a> {
+    Hello { version: u32 },
+    UnsupportedVersion { version: u32 },
+    Error { code: &'static str, message: String },
+    Login { user: String, name: String, color: u32, session: String },
+    Registered { user: String, name: String, color: u32, session: String },
+    PasswordChanged,
+    Renamed { name: String },
+    AccountDeleted,
+    Unlocked { username: String },
+    Wrong,
+    NotExists,
+    MyColor { data: u32 },
+    MyRole { role: Role },
+    /// The live strokes of every client since the last tick
+    DrawTmp { strokes: Vec<Stroke> },
+    Redraw { version: u64, data: Vec<&'a RawValue> },
+    LineAdded { version: u64, line: Box<RawValue> },
+    LineHidden { version: u64, id: String },
+    /// Every line of `author` is gone, after a `clear` or a deleted account
+    LinesCleared { version: u64, author: String },
+    LineRestored { version: u64, line: Box<RawValue> },
+    /// The events missed since `resume`, in order, all in one message
+    Replay { events: Vec<Box<RawValue>> },
+    Boards { data: Vec<String> },
+    BoardCreated { name: String },
+    BoardRenamed { name: String, new_name: String },
+    BoardDeleted { name: String },
+    Granted { board: String, username: String, role: Role },
+    Revoked { board: String, username: String },
+    Invite { invite: String, board: String, role: Role, expires: u64 },
+    InviteRevoked,
+    /// The token to give along with the invite when reconnecting
+    Redeemed { guest: String },
+    Presence { clients: usize, spectators: usize },
+    /// Also sent again when the name or the color of the member changes
+    UserJoined(Member),
+    UserLeft { id: usize },
+    Who { users: Vec<Member>, spectators: usize },
+    Cursor { id: usize, x: i32, y: i32 },
+    Chat(ChatEntry),
+    ChatHistory { messages: Vec<ChatEntry>, more: bool },
+    /// The server is going down; reconnect in a while
+    ServerShutdown,
+}
+
+/// Displays the message as it goes on the wire
+impl fmt::Display for ServerMsg<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&s)
+    }
+}
@@ -0,0 +1,259 @@
+use crate::protocol::ClientMsg;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Users are allowed this many times the limits of a single connection, so
+/// that they can keep a few tabs open
+const USER_SCALE: f64 = 2.0;
+
+/// Limits broken more often than this get the connection kicked
+pub const STRIKES: Limit = Limit { rate: 1.0, burst: 10.0 };
+
+/// The users whose limits were not used for this long are forgotten
+const FORGET_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    /// Tokens added per second
+    pub rate: f64,
+    /// The most tokens a bucket holds
+    pub burst: f64,
+}
+
+/// Commands that share a limit
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Class {
+    /// Live strokes and cursors, sent on every mouse move
+    Live,
+    /// Finished lines, undo and redo
+    Draw,
+    Clear,
+    Chat,
+    /// Signing in and everything that checks a password
+    Auth,
+    /// Changes to boards, roles, invites and accounts
+    Manage,
+    /// Queries that only read
+    Read,
+}
+
+impl Class {
+    pub fn of(cmd: &ClientMsg) -> Class {
+        match cmd {
+            ClientMsg::DrawTmp { .. } | ClientMsg::Cursor { .. } => Class::Live,
+            ClientMsg::Draw { .. } | ClientMsg::Undo | ClientMsg::Redo => Class::Draw,
+            ClientMsg::Clear => Class::Clear,
+            ClientMsg::Chat { .. } => Class::Chat,
+            ClientMsg::Login { .. }
+            | ClientMsg::NewUser { .. }
+            | ClientMsg::Auth { .. }
+            | ClientMsg::Redeem { .. }
+            | ClientMsg::ChangePassword { .. }
+            | ClientMsg::DeleteAccount { .. } => Class::Auth,
+            ClientMsg::Logout
+            | ClientMsg::Rename { .. }
+            | ClientMsg::Unlock { .. }
+            | ClientMsg::SetColor { .. }
+            | ClientMsg::CreateBoard { .. }
+            | ClientMsg::RenameBoard { .. }
+            | ClientMsg::DeleteBoard { .. }
+            | ClientMsg::Grant { .. }
+            | ClientMsg::Revoke { .. }
+            | ClientMsg::CreateInvite { .. }
+            | ClientMsg::RevokeInvite { .. } => Class::Manage,
+            ClientMsg::Hello { .. }
+            | ClientMsg::Redraw
+            | ClientMsg::Refresh
+            | ClientMsg::Resume { .. }
+            | ClientMsg::ListBoards
+            | ClientMsg::Who
+            | ClientMsg::ChatHistory { .. } => Class::Read,
+        }
+    }
+
+    /// The limit of a single connection
+    pub fn limit(self) -> Limit {
+        let (rate, burst) = match self {
+            Class::Live => (120.0, 240.0),
+            Class::Draw => (10.0, 20.0),
+            Class::Clear => (1.0, 3.0),
+            Class::Chat => (2.0, 5.0),
+            Class::Auth => (1.0, 5.0),
+            Class::Manage => (1.0, 5.0),
+            Class::Read => (5.0, 10.0),
+        };
+        Limit { rate, burst }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Class::Live => "live",
+            Class::Draw => "draw",
+            Class::Clear => "clear",
+            Class::Chat => "chat",
+            Class::Auth => "auth",
+            Class::Manage => "manage",
+            Class::Read => "read",
+        }
+    }
+}
+
+/// A token bucket, that starts full
+#[derive(Clone, Copy, Debug)]
+pub struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    pub fn new(limit: Limit) -> Self {
+        Bucket {
+            tokens: limit.burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a token, if there is one left
+    pub fn take(&mut self, limit: Limit) -> bool {
+        self.take_at(limit, Instant::now())
+    }
+
+    fn take_at(&mut self, limit: Limit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The buckets of a connection or a user, one per class
+#[derive(Default)]
+pub struct Limiter {
+    buckets: HashMap<Class, Bucket>,
+}
+
+impl Limiter {
+    fn take(&mut self, class: Class, limit: Limit) -> bool {
+        self.buckets
+            .entry(class)
+            .or_insert_with(|| Bucket::new(limit))
+            .take(limit)
+    }
+
+    /// Takes a token for a command of `class` from a connection
+    pub fn allow(&mut self, class: Class) -> bool {
+        self.take(class, class.limit())
+    }
+}
+
+/// The limiters of every user, shared by all their connections
+#[derive(Default)]
+pub struct UserLimits {
+    users: Mutex<HashMap<[u8; 16], (Limiter, Instant)>>,
+}
+
+impl UserLimits {
+    /// Takes a token for a command of `class` from a user
+    pub fn allow(&self, user: [u8; 16], class: Class) -> bool {
+        let mut users = self.users.lock().unwrap();
+        let now = Instant::now();
+        if users.len() > 1024 {
+            users.retain(|_, (_, last)| now.duration_since(*last) < FORGET_AFTER);
+        }
+        let (limiter, last) = users.entry(user).or_insert_with(|| (Limiter::default(), now));
+        *last = now;
+        let limit = class.limit();
+        limiter.take(
+            class,
+            Limit {
+                rate: limit.rate * USER_SCALE,
+                burst: limit.burst * USER_SCALE,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Limit = Limit { rate: 2.0, burst: 3.0 };
+
+    fn drained() -> Bucket {
+        let mut bucket = Bucket::new(LIMIT);
+        let now = bucket.last;
+        for _ in 0..3 {
+            assert!(bucket.take_at(LIMIT, now));
+        }
+        assert!(!bucket.take_at(LIMIT, now));
+        bucket
+    }
+
+    #[test]
+    fn a_bucket_starts_full() {
+        drained();
+    }
+
+    #[test]
+    fn a_bucket_refills_at_its_rate() {
+        let mut bucket = drained();
+        let start = bucket.last;
+        assert!(!bucket.take_at(LIMIT, start + Duration::from_millis(250)));
+        assert!(bucket.take_at(LIMIT, start + Duration::from_millis(500)));
+        assert!(!bucket.take_at(LIMIT, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn a_bucket_holds_at_most_its_burst() {
+        let mut bucket = drained();
+        let now = bucket.last + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.take_at(LIMIT, now));
+        }
+        assert!(!bucket.take_at(LIMIT, now));
+    }
+
+    /// Takes every token of `class` from the limiter
+    fn drain(limiter: &mut Limiter, class: Class) {
+        for _ in 0..class.limit().burst as usize {
+            assert!(limiter.allow(class));
+        }
+        assert!(!limiter.allow(class));
+    }
+
+    #[test]
+    fn a_limiter_keeps_classes_apart() {
+        let mut limiter = Limiter::default();
+        drain(&mut limiter, Class::Clear);
+        assert!(limiter.allow(Class::Chat));
+        assert!(limiter.allow(Class::Draw));
+        assert!(!limiter.allow(Class::Clear));
+    }
+
+    #[test]
+    fn a_limiter_refills() {
+        let mut limiter = Limiter::default();
+        drain(&mut limiter, Class::Clear);
+        let bucket = limiter.buckets.get_mut(&Class::Clear).unwrap();
+        bucket.last = bucket.last.checked_sub(Duration::from_secs(1)).unwrap();
+        assert!(limiter.allow(Class::Clear));
+        assert!(!limiter.allow(Class::Clear));
+    }
+
+    #[test]
+    fn users_get_more_than_a_connection() {
+        let limits = UserLimits::default();
+        let burst = (Class::Clear.limit().burst * USER_SCALE) as usize;
+        for _ in 0..burst {
+            assert!(limits.allow([1; 16], Class::Clear));
+        }
+        assert!(!limits.allow([1; 16], Class::Clear));
+        assert!(limits.allow([2; 16], Class::Clear));
+    }
+}
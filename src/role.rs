@@ -0,0 +1,58 @@
+use crate::protocol::ClientMsg;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What a user may do on a board. Every role can do whatever the roles
+/// before it can.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Watches the board
+    Viewer,
+    /// Draws, and undoes, redoes or clears their own lines
+    Editor,
+    /// Renames and deletes the board, and grants and revokes roles on it
+    Owner,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        })
+    }
+}
+
+/// Whether spectators may send the command. They may identify themselves,
+/// but nothing that changes a board or an account.
+pub fn read_only(cmd: &ClientMsg) -> bool {
+    matches!(
+        cmd,
+        ClientMsg::Hello { .. }
+            | ClientMsg::Auth { .. }
+            | ClientMsg::Redeem { .. }
+            | ClientMsg::Redraw
+            | ClientMsg::Refresh
+            | ClientMsg::Resume { .. }
+            | ClientMsg::ListBoards
+            | ClientMsg::Who
+            | ClientMsg::ChatHistory { .. }
+    )
+}
+
+/// The role needed on the connection's board to run the command, if any.
+///
+/// Commands on other boards, such as `rename_board`, check the role on the
+/// board they name.
+pub fn required(cmd: &ClientMsg) -> Option<Role> {
+    match cmd {
+        ClientMsg::Draw { .. }
+        | ClientMsg::DrawTmp { .. }
+        | ClientMsg::Undo
+        | ClientMsg::Redo
+        | ClientMsg::Clear => Some(Role::Editor),
+        _ => None,
+    }
+}
@@ -0,0 +1,175 @@
+use crate::board::Snapshot;
+use crate::metrics::{Metrics, METRICS};
+use crate::outbox::{Kind, Outbox};
+use crate::protocol::{Member, Point, ServerMsg, Stroke};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+use warp::ws::Message;
+
+pub type Tx = Arc<Outbox>;
+
+pub fn send(tx: &Tx, msg: String) {
+    tx.push(Kind::Durable, Message::text(msg));
+}
+
+/// A client connected to a board
+pub struct Client {
+    pub tx: Tx,
+    /// Spectators only watch, and are counted apart from the others
+    pub spectator: bool,
+    /// Who the client is, once it has signed in or joined as a guest
+    pub member: Option<Member>,
+}
+
+/// The volatile state of a board
+pub struct Room {
+    /// The key of the board in the pool
+    pub id: [u8; 16],
+    /// The materialized view of the visible lines
+    pub snapshot: RwLock<Snapshot>,
+    /// Our state of currently connected users.
+    ///
+    /// - Key is their id
+    /// - Value holds a sender of `warp::ws::Message`
+    pub clients: RwLock<HashMap<usize, Client>>,
+    /// The live stroke segments received since the last tick, by the id of
+    /// the connection that drew them
+    strokes: Mutex<HashMap<usize, Stroke>>,
+}
+
+impl Room {
+    pub fn new(id: [u8; 16], snapshot: Snapshot) -> Self {
+        Room {
+            id,
+            snapshot: RwLock::new(snapshot),
+            clients: Default::default(),
+            strokes: Default::default(),
+        }
+    }
+
+    /// Holds a live stroke segment until the next tick
+    pub fn draw_tmp(&self, author: usize, color: u32, data: Vec<Point>) {
+        let mut strokes = self.strokes.lock().unwrap();
+        let stroke = strokes.entry(author).or_insert_with(|| Stroke {
+            author,
+            color,
+            segments: vec![],
+        });
+        stroke.color = color;
+        stroke.segments.push(data);
+    }
+
+    /// Sends the live stroke segments held since the last tick to everyone,
+    /// as a single frame
+    pub async fn flush(&self) {
+        let strokes: Vec<Stroke> = {
+            let mut strokes = self.strokes.lock().unwrap();
+            if strokes.is_empty() {
+                return;
+            }
+            strokes.drain().map(|(_, stroke)| stroke).collect()
+        };
+        let msg = ServerMsg::DrawTmp { strokes }.to_string();
+        let recipients = self.clients.read().await.len();
+        self.relay(None, Kind::Droppable, &msg).await;
+        Metrics::add(&METRICS.frames_sent, recipients as u64);
+    }
+
+    /// Sends a message to every client on the board
+    pub async fn broadcast(&self, msg: &str) {
+        self.relay(None, Kind::Durable, msg).await;
+    }
+
+    /// Sends a message to every client on the board but `from`, if given
+    pub async fn relay(&self, from: Option<usize>, kind: Kind, msg: &str) {
+        for (id, client) in self.clients.read().await.iter() {
+            if Some(*id) != from {
+                client.tx.push(kind, Message::text(msg));
+            }
+        }
+    }
+
+    /// Shows the client to the others as `member`
+    pub async fn join(&self, id: usize, member: Member) {
+        if let Some(client) = self.clients.write().await.get_mut(&id) {
+            client.member = Some(member);
+        }
+    }
+
+    /// Stops showing the client to the others, and returns whether it was
+    pub async fn leave(&self, id: usize) -> bool {
+        match self.clients.write().await.get_mut(&id) {
+            Some(client) => client.member.take().is_some(),
+            None => false,
+        }
+    }
+
+    pub async fn member(&self, id: usize) -> Option<Member> {
+        self.clients.read().await.get(&id).and_then(|c| c.member.clone())
+    }
+
+    /// The clients shown to the others, in the order they connected
+    pub async fn members(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = self
+            .clients
+            .read()
+            .await
+            .values()
+            .filter_map(|c| c.member.clone())
+            .collect();
+        members.sort_by_key(|m| m.id);
+        members
+    }
+
+    /// Closes the connection of every client on the board
+    pub async fn close(&self) {
+        for client in self.clients.read().await.values() {
+            client.tx.close();
+        }
+    }
+
+    /// Counts the connected clients, as participants and spectators
+    pub async fn count(&self) -> (usize, usize) {
+        let clients = self.clients.read().await;
+        let spectators = clients.values().filter(|c| c.spectator).count();
+        (clients.len() - spectators, spectators)
+    }
+}
+
+/// The boards known to the server, by name
+#[derive(Default)]
+pub struct Rooms {
+    rooms: HashMap<String, Arc<Room>>,
+}
+
+impl Rooms {
+    pub fn get(&self, name: &str) -> Option<Arc<Room>> {
+        self.rooms.get(name).cloned()
+    }
+
+    pub fn insert(&mut self, name: String, room: Arc<Room>) {
+        self.rooms.insert(name, room);
+    }
+
+    pub fn rename(&mut self, name: &str, new_name: String) {
+        if let Some(room) = self.rooms.remove(name) {
+            self.rooms.insert(new_name, room);
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Arc<Room>> {
+        self.rooms.remove(name)
+    }
+
+    /// The names of all boards, in alphabetical order
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.rooms.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn all(&self) -> Vec<Arc<Room>> {
+        self.rooms.values().cloned().collect()
+    }
+}
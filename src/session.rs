@@ -0,0 +1,97 @@
+use crate::hashmap::HashMap as PHashMap;
+use corundum::default::*;
+use hex::FromHex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type P = BuddyAlloc;
+
+/// How long a session stays valid after signing in, in seconds
+pub const SESSION_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Parses a session token given in hex
+pub fn parse_token(s: &str) -> Option<[u8; 16]> {
+    <[u8; 16]>::from_hex(s).ok()
+}
+
+#[derive(Clone, Copy)]
+pub struct Session {
+    pub user: [u8; 16],
+    pub expires: u64,
+}
+
+/// The open sessions, keyed by their random token
+pub struct Sessions {
+    tokens: PHashMap<[u8; 16], Session>,
+}
+
+impl RootObj<P> for Sessions {
+    fn init(j: &Journal) -> Self {
+        Sessions {
+            tokens: RootObj::init(j),
+        }
+    }
+}
+
+impl Sessions {
+    /// Opens a new session for `user` and returns its token
+    pub fn open(&mut self, user: [u8; 16], j: &Journal) -> [u8; 16] {
+        self.purge(j);
+        let token: [u8; 16] = rand::random();
+        self.tokens.put(
+            token,
+            Session {
+                user,
+                expires: now() + SESSION_TTL,
+            },
+            j,
+        );
+        token
+    }
+
+    /// Returns the user of the session, if it is still valid
+    pub fn check(&self, token: &[u8; 16]) -> Option<[u8; 16]> {
+        match self.tokens.get(*token) {
+            Some(s) if s.expires > now() => Some(s.user),
+            _ => None,
+        }
+    }
+
+    pub fn close(&mut self, token: &[u8; 16], j: &Journal) -> bool {
+        self.tokens.remove(token, j)
+    }
+
+    /// Closes all sessions of `user`, except `keep`
+    pub fn close_all(&mut self, user: [u8; 16], keep: Option<[u8; 16]>, j: &Journal) {
+        let mut tokens = vec![];
+        self.tokens.foreach(|token, s| {
+            if s.user == user && Some(*token) != keep {
+                tokens.push(*token);
+            }
+        });
+        for token in tokens {
+            self.tokens.remove(&token, j);
+        }
+    }
+
+    /// Drops the expired sessions
+    pub fn purge(&mut self, j: &Journal) {
+        let now = now();
+        let mut expired = vec![];
+        self.tokens.foreach(|token, s| {
+            if s.expires <= now {
+                expired.push(*token);
+            }
+        });
+        for token in expired {
+            self.tokens.remove(&token, j);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::board::LineId;
+use crate::hashmap::HashMap as PHashMap;
+use crate::history::History;
+use corundum::default::*;
+use std::fmt::Write;
+use std::time::SystemTime;
+
+/// The width of the lines, as drawn by the whiteboard
+const STROKE_WIDTH: i32 = 3;
+
+/// The blank space around the drawing
+const MARGIN: i32 = 10;
+
+/// A line to render: its id, color and points
+pub type Drawn = (LineId, u32, Vec<(i32, i32)>);
+
+/// Which of the visible lines to export
+#[derive(Default)]
+pub struct Filter {
+    /// Only the lines of this user
+    pub author: Option<[u8; 16]>,
+    /// Only the lines drawn at or after this time
+    pub since: Option<SystemTime>,
+    /// Only the lines drawn before this time
+    pub until: Option<SystemTime>,
+}
+
+impl Filter {
+    fn accepts(&self, id: &LineId) -> bool {
+        self.author.iter().all(|author| *author == id.1)
+            && self.since.iter().all(|since| id.0 >= *since)
+            && self.until.iter().all(|until| id.0 < *until)
+    }
+}
+
+/// Renders the visible lines of a board into a standalone SVG document,
+/// in the order they were drawn
+pub fn export(histories: &PHashMap<[u8; 16], History>, filter: &Filter, j: &Journal) -> String {
+    let mut lines = vec![];
+    histories.foreach(|user, history| {
+        history.foreach_visible(j, |line| {
+            let id = (line.timestamp(), *user);
+            if filter.accepts(&id) {
+                lines.push((id, line.color(), line.points()));
+            }
+        });
+    });
+    lines.sort_by(|a, b| a.0.cmp(&b.0));
+    render(&lines)
+}
+
+/// Renders lines given as their id, color and points
+pub fn render(lines: &[Drawn]) -> String {
+    let points = lines.iter().flat_map(|(_, _, points)| points.iter());
+    let (min_x, min_y, max_x, max_y) = points.fold(
+        (i32::MAX, i32::MAX, i32::MIN, i32::MIN),
+        |(min_x, min_y, max_x, max_y), &(x, y)| (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)),
+    );
+    let (x, y, width, height) = if min_x > max_x {
+        (0, 0, 2 * MARGIN, 2 * MARGIN)
+    } else {
+        (
+            min_x - MARGIN,
+            min_y - MARGIN,
+            max_x - min_x + 2 * MARGIN,
+            max_y - min_y + 2 * MARGIN,
+        )
+    };
+
+    let mut svg = String::new();
+    let _ = writeln!(svg, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="{x} {y} {w} {h}">"#,
+        x = x,
+        y = y,
+        w = width,
+        h = height
+    );
+    let _ = writeln!(
+        svg,
+        r#"<rect x="{}" y="{}" width="{}" height="{}" fill="white"/>"#,
+        x, y, width, height
+    );
+    for (_, color, points) in lines {
+        match points.as_slice() {
+            [] => {}
+            // A dot, which a polyline would not show
+            [(x, y)] => {
+                let _ = writeln!(
+                    svg,
+                    r##"<circle cx="{}" cy="{}" r="{}" fill="#{:06x}"/>"##,
+                    x,
+                    y,
+                    STROKE_WIDTH as f32 / 2.0,
+                    color
+                );
+            }
+            points => {
+                let coords: Vec<String> = points.iter().map(|(x, y)| format!("{},{}", x, y)).collect();
+                let _ = writeln!(
+                    svg,
+                    r##"<polyline points="{}" fill="none" stroke="#{:06x}" stroke-width="{}" stroke-linecap="round" stroke-linejoin="round"/>"##,
+                    coords.join(" "),
+                    color,
+                    STROKE_WIDTH
+                );
+            }
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn line(secs: u64, color: u32, points: &[(i32, i32)]) -> Drawn {
+        ((UNIX_EPOCH + Duration::from_secs(secs), [0; 16]), color, points.to_vec())
+    }
+
+    #[test]
+    fn empty_board() {
+        let svg = render(&[]);
+        assert!(svg.starts_with("<?xml"), "{}", svg);
+        assert!(svg.ends_with("</svg>\n"), "{}", svg);
+        assert!(svg.contains(r#"width="20" height="20" viewBox="0 0 20 20""#), "{}", svg);
+        assert!(!svg.contains("<polyline") && !svg.contains("<circle"), "{}", svg);
+    }
+
+    #[test]
+    fn a_single_point_is_a_dot() {
+        let svg = render(&[line(1, 0xff0000, &[(5, -7)])]);
+        assert!(svg.contains(r##"<circle cx="5" cy="-7" r="1.5" fill="#ff0000"/>"##), "{}", svg);
+        assert!(!svg.contains("<polyline"), "{}", svg);
+        assert!(svg.contains(r#"viewBox="-5 -17 20 20""#), "{}", svg);
+    }
+
+    #[test]
+    fn the_view_box_fits_every_line_with_a_margin() {
+        let svg = render(&[
+            line(1, 0x0000ff, &[(10, 20), (30, 5)]),
+            line(2, 0x00ff00, &[(-40, 50), (0, 0), (2, 3)]),
+        ]);
+        // From (-40, 0) to (30, 50), and 10 more on every side
+        assert!(svg.contains(r#"width="90" height="70" viewBox="-50 -10 90 70""#), "{}", svg);
+        assert!(svg.contains(r#"<rect x="-50" y="-10" width="90" height="70" fill="white"/>"#), "{}", svg);
+        assert!(svg.contains(r##"<polyline points="10,20 30,5" fill="none" stroke="#0000ff""##), "{}", svg);
+        assert!(svg.contains(r##"<polyline points="-40,50 0,0 2,3" fill="none" stroke="#00ff00""##), "{}", svg);
+        assert!(svg.find("10,20") < svg.find("-40,50"), "{}", svg);
+    }
+}
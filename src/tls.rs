@@ -0,0 +1,283 @@
+use crate::config::Tls;
+use log::{info, warn};
+use rustls::internal::pemfile;
+use rustls::sign;
+use rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig, SignatureScheme};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{oneshot, watch};
+use warp::filters::BoxedFilter;
+
+/// Every route of the server, boxed so that it can be served again
+pub type Routes = BoxedFilter<(Box<dyn warp::Reply>,)>;
+
+/// The schemes a key is tried with to tell whether it goes with the
+/// certificate, and how webpki calls them
+const SCHEMES: [(SignatureScheme, &webpki::SignatureAlgorithm); 4] = [
+    (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+    (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (SignatureScheme::RSA_PSS_SHA256, &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY),
+];
+
+/// Checks that the certificate and the key can be loaded, so that a broken
+/// renewal does not take the server down
+pub fn check(tls: &Tls) -> Result<(), String> {
+    let certs = pemfile::certs(&mut open(&tls.cert)?)
+        .map_err(|_| format!("{} holds no valid certificate", tls.cert.display()))?;
+    if certs.is_empty() {
+        return Err(format!("{} holds no certificate", tls.cert.display()));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(&tls.key)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(&tls.key)?).unwrap_or_default();
+    }
+    let key = match keys.pop() {
+        Some(key) => key,
+        None => return Err(format!("{} holds no private key", tls.key.display())),
+    };
+    if !matches(&certs[0], &key) {
+        return Err(format!("{} does not go with {}", tls.key.display(), tls.cert.display()));
+    }
+    ServerConfig::new(NoClientAuth::new())
+        .set_single_cert(certs, key)
+        .map_err(|e| format!("{} cannot be used: {}", tls.key.display(), e))
+}
+
+/// Whether the key signs what the certificate verifies. rustls takes any
+/// pair, and only fails the handshakes later.
+fn matches(cert: &Certificate, key: &PrivateKey) -> bool {
+    let signer = match sign::any_supported_type(key) {
+        Ok(key) => {
+            let offered: Vec<SignatureScheme> = SCHEMES.iter().map(|(scheme, _)| *scheme).collect();
+            key.choose_scheme(&offered)
+        }
+        Err(()) => None,
+    };
+    let signer = match signer {
+        Some(signer) => signer,
+        None => return false,
+    };
+    let alg = match SCHEMES.iter().find(|(scheme, _)| *scheme == signer.get_scheme()) {
+        Some((_, alg)) => *alg,
+        None => return false,
+    };
+    let msg = b"whiteboard";
+    match (signer.sign(msg), webpki::EndEntityCert::from(&cert.0)) {
+        (Ok(sig), Ok(cert)) => cert.verify_signature(alg, msg, &sig).is_ok(),
+        _ => false,
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("cannot read {}: {}", path.display(), e))
+}
+
+/// When the certificate and the key were last written
+fn stamp(tls: &Tls) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(&tls.cert), modified(&tls.key))
+}
+
+/// Serves `routes` over TLS on `addr` until `stopped` turns true. The server
+/// is restarted with the new certificate whenever its files change; the
+/// connections already upgraded to WebSockets are kept.
+pub async fn serve(
+    routes: Routes,
+    addr: SocketAddr,
+    tls: &'static Tls,
+    stopped: watch::Receiver<bool>,
+) {
+    let interval = Duration::from_secs(tls.reload_interval);
+    let mut loaded = stamp(tls);
+    loop {
+        let (reload, reloaded) = oneshot::channel::<()>();
+        let mut stop = stopped.clone();
+        let (_, serving) = warp::serve(routes.clone())
+            .tls()
+            .cert_path(&tls.cert)
+            .key_path(&tls.key)
+            .bind_with_graceful_shutdown(addr, async move {
+                let stopping = async { while let Some(false) = stop.recv().await {} };
+                tokio::select! {
+                    _ = reloaded => {}
+                    _ = stopping => {}
+                }
+            });
+        let mut serving = tokio::task::spawn(serving);
+
+        // Wait for new files that load, unless the server stops first
+        let mut poll = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = &mut serving => return,
+                _ = poll.tick() => {
+                    let current = stamp(tls);
+                    if current == loaded {
+                        continue;
+                    }
+                    loaded = current;
+                    match check(tls) {
+                        Ok(()) => break,
+                        Err(e) => warn!("not reloading the certificate: {}", e),
+                    }
+                }
+            }
+        }
+
+        info!("reloading the certificate for {}", addr);
+        let _ = reload.send(());
+        let _ = serving.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConfig, ClientSession, RootCertStore, ServerCertVerified, ServerCertVerifier, Session, TLSError};
+    use std::env;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls").join(name)
+    }
+
+    fn pair(cert: PathBuf, key: PathBuf) -> Tls {
+        Tls {
+            cert,
+            key,
+            reload_interval: 1,
+        }
+    }
+
+    /// An empty directory of its own for a test
+    fn scratch(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("wb-tls-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The certificate of a fixture, in DER
+    fn der(name: &str) -> Vec<u8> {
+        pemfile::certs(&mut open(&fixture(name)).unwrap()).unwrap().remove(0).0
+    }
+
+    #[test]
+    fn accepts_a_matching_pair() {
+        assert_eq!(check(&pair(fixture("cert-a.pem"), fixture("key-a.pem"))), Ok(()));
+        assert_eq!(check(&pair(fixture("cert-b.pem"), fixture("key-b.pem"))), Ok(()));
+    }
+
+    #[test]
+    fn rejects_the_key_of_another_certificate() {
+        let e = check(&pair(fixture("cert-a.pem"), fixture("key-b.pem"))).unwrap_err();
+        assert!(e.contains("does not go with"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_empty_files() {
+        let dir = scratch("empty");
+        let empty = dir.join("empty.pem");
+        fs::write(&empty, "").unwrap();
+
+        let e = check(&pair(empty.clone(), fixture("key-a.pem"))).unwrap_err();
+        assert!(e.contains("holds no certificate"), "{}", e);
+        let e = check(&pair(fixture("cert-a.pem"), empty)).unwrap_err();
+        assert!(e.contains("holds no private key"), "{}", e);
+        // Nor is a certificate a key
+        let e = check(&pair(fixture("cert-a.pem"), fixture("cert-a.pem"))).unwrap_err();
+        assert!(e.contains("holds no private key"), "{}", e);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rejects_missing_files() {
+        let missing = scratch("missing").join("missing.pem");
+        let e = check(&pair(missing.clone(), fixture("key-a.pem"))).unwrap_err();
+        assert!(e.starts_with("cannot read"), "{}", e);
+        let e = check(&pair(fixture("cert-a.pem"), missing)).unwrap_err();
+        assert!(e.starts_with("cannot read"), "{}", e);
+    }
+
+    /// Trusts any certificate, and keeps the one the server presented
+    struct Presented(Arc<Mutex<Option<Vec<u8>>>>);
+
+    impl ServerCertVerifier for Presented {
+        fn verify_server_cert(
+            &self,
+            _roots: &RootCertStore,
+            certs: &[Certificate],
+            _name: webpki::DNSNameRef<'_>,
+            _ocsp: &[u8],
+        ) -> Result<ServerCertVerified, TLSError> {
+            *self.0.lock().unwrap() = certs.first().map(|cert| cert.0.clone());
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    /// Completes a new handshake with the server on `port`, and returns the
+    /// certificate it presented
+    fn handshake(port: u16) -> Result<Vec<u8>, String> {
+        let presented = Arc::new(Mutex::new(None));
+        let mut config = ClientConfig::new();
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(Presented(presented.clone())));
+        let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut session = ClientSession::new(&Arc::new(config), name);
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        while session.is_handshaking() {
+            session.complete_io(&mut socket).map_err(|e| e.to_string())?;
+        }
+        let cert = presented.lock().unwrap().take();
+        cert.ok_or_else(|| "no certificate was presented".to_string())
+    }
+
+    /// Keeps shaking hands with the server for a few seconds, until it
+    /// presents `cert`
+    async fn presents(port: u16, cert: &[u8]) -> bool {
+        for _ in 0..50 {
+            match tokio::task::spawn_blocking(move || handshake(port)).await {
+                Ok(Ok(presented)) if presented == cert => return true,
+                _ => tokio::time::delay_for(Duration::from_millis(100)).await,
+            }
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn serve_reloads_a_renewed_certificate() {
+        let dir = scratch("reload");
+        fs::copy(fixture("cert-a.pem"), dir.join("cert.pem")).unwrap();
+        fs::copy(fixture("key-a.pem"), dir.join("key.pem")).unwrap();
+        let tls: &'static Tls = Box::leak(Box::new(pair(dir.join("cert.pem"), dir.join("key.pem"))));
+
+        // `serve` binds the address again on every reload, so it needs a
+        // fixed port
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let routes: Routes = warp::any().map(|| Box::new("ok") as Box<dyn warp::Reply>).boxed();
+        let (stop, stopped) = watch::channel(false);
+        let server = tokio::task::spawn(serve(routes, addr, tls, stopped));
+        assert!(presents(port, &der("cert-a.pem")).await, "the certificate was not served");
+
+        // Renewals may leave the files mismatched for a moment; the old pair
+        // is served until the new one loads
+        fs::copy(fixture("key-b.pem"), dir.join("key.pem")).unwrap();
+        fs::copy(fixture("cert-b.pem"), dir.join("cert.pem")).unwrap();
+        assert!(presents(port, &der("cert-b.pem")).await, "the renewed certificate was not served");
+
+        let _ = stop.broadcast(true);
+        server.await.unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,530 @@
+use crate::invite::MAX_INVITE_TTL;
+use crate::protocol::{ClientMsg, Point, ServerMsg};
+use crate::role::Role;
+use crate::session;
+use std::fmt;
+
+/// The default maximum size of a message, in bytes, checked before parsing
+/// it. A line of `MAX_POINTS` points fits comfortably.
+pub const MAX_MESSAGE_LEN: usize = 512 * 1024;
+
+/// The maximum number of points in a finished line
+pub const MAX_POINTS: usize = 10_000;
+
+/// The maximum number of points in a live stroke segment
+pub const MAX_TMP_POINTS: usize = 64;
+
+/// Coordinates must lie within `-MAX_COORD..=MAX_COORD`
+pub const MAX_COORD: i32 = 1 << 16;
+
+/// The maximum length of a username, in characters
+pub const MAX_NAME_LEN: usize = 64;
+
+/// The minimum length of a new password, in characters
+pub const MIN_PASSWORD_LEN: usize = 1;
+
+/// The maximum length of a password, in characters
+pub const MAX_PASSWORD_LEN: usize = 256;
+
+/// The maximum length of a board name
+pub const MAX_BOARD_NAME_LEN: usize = 64;
+
+/// The maximum length of an invite token, in characters
+pub const MAX_INVITE_LEN: usize = 256;
+
+/// The maximum length of a chat message, in characters
+pub const MAX_CHAT_LEN: usize = 2000;
+
+/// A rejected request, reported back to the client as an `error` message
+#[derive(Debug)]
+pub struct Invalid {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Invalid {
+    pub fn new<S: Into<String>>(code: &'static str, message: S) -> Self {
+        Invalid {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// The message that answers a failed sign-in or sign-up. The login page
+    /// predates the `error` message for wrong passwords and unknown users,
+    /// so these two keep their own.
+    pub fn login_reply(self) -> ServerMsg<'static> {
+        match self.code {
+            "wrong" => ServerMsg::Wrong,
+            "not_exists" => ServerMsg::NotExists,
+            _ => ServerMsg::Error { code: self.code, message: self.message },
+        }
+    }
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+/// Parses a message from a client and checks its arguments
+pub fn parse(msg: &str) -> Result<ClientMsg, Invalid> {
+    let cmd = serde_json::from_str::<ClientMsg>(msg)
+        .map_err(|e| Invalid::new("bad_request", e.to_string()))?;
+    check(&cmd)?;
+    Ok(cmd)
+}
+
+/// Checks the arguments of a command before it reaches the pool
+pub fn check(cmd: &ClientMsg) -> Result<(), Invalid> {
+    match cmd {
+        ClientMsg::Login { username, password } => {
+            name(username)?;
+            text("password", password, MAX_PASSWORD_LEN)
+        }
+        ClientMsg::NewUser { username, password } => {
+            name(username)?;
+            new_password(password)
+        }
+        ClientMsg::ChangePassword { old_password, new_password: password } => {
+            text("password", old_password, MAX_PASSWORD_LEN)?;
+            new_password(password)
+        }
+        ClientMsg::Rename { name: new_name } => name(new_name),
+        ClientMsg::Unlock { username } => name(username),
+        ClientMsg::DeleteAccount { password } => text("password", password, MAX_PASSWORD_LEN),
+        ClientMsg::Auth { session: Some(session) } => match session::parse_token(session) {
+            Some(_) => Ok(()),
+            None => Err(Invalid::new("bad_session", "malformed session token")),
+        },
+        ClientMsg::CreateBoard { name } | ClientMsg::DeleteBoard { name } => board_name(name),
+        ClientMsg::RenameBoard { name, new_name } => {
+            board_name(name)?;
+            board_name(new_name)
+        }
+        ClientMsg::Grant { board, username, .. } | ClientMsg::Revoke { board, username } => {
+            board_name(board)?;
+            name(username)
+        }
+        ClientMsg::CreateInvite { board, role, expires_in, max_uses } => {
+            board_name(board)?;
+            if *role == Role::Owner {
+                return Err(Invalid::new("bad_role", "invites can only make viewers or editors"));
+            }
+            if *expires_in == 0 || *expires_in > MAX_INVITE_TTL {
+                return Err(Invalid::new(
+                    "bad_expiry",
+                    format!("invites must expire within {} seconds", MAX_INVITE_TTL),
+                ));
+            }
+            if *max_uses == 0 {
+                return Err(Invalid::new("bad_uses", "invites must be usable at least once"));
+            }
+            Ok(())
+        }
+        ClientMsg::Redeem { invite, guest } => {
+            if let Some(guest) = guest {
+                text("guest", guest, MAX_INVITE_LEN)?;
+            }
+            text("invite", invite, MAX_INVITE_LEN)
+        }
+        ClientMsg::RevokeInvite { invite } => text("invite", invite, MAX_INVITE_LEN),
+        ClientMsg::Chat { text: message } => {
+            if message.trim().is_empty() {
+                return Err(Invalid::new("empty_message", "chat messages must not be empty"));
+            }
+            text("message", message, MAX_CHAT_LEN)
+        }
+        ClientMsg::SetColor { data } => color(data).map(|_| ()),
+        ClientMsg::Draw { data } => points(data, MAX_POINTS),
+        ClientMsg::Cursor { x, y } => points(&[Point { x: *x, y: *y }], 1),
+        ClientMsg::DrawTmp { color, data } => {
+            if *color > 0xff_ffff {
+                return Err(Invalid::new("bad_color", "color must be a 24-bit RGB value"));
+            }
+            points(data, MAX_TMP_POINTS)
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn name(name: &str) -> Result<(), Invalid> {
+    if name.trim().is_empty() {
+        return Err(Invalid::new("bad_name", "username must not be empty"));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(Invalid::new("bad_name", "username must not contain control characters"));
+    }
+    text("username", name, MAX_NAME_LEN)
+}
+
+/// Checks a password being set; older accounts may have shorter ones, so
+/// signing in does not
+fn new_password(password: &str) -> Result<(), Invalid> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(Invalid::new(
+            "bad_password",
+            format!("password must be at least {} characters long", MIN_PASSWORD_LEN),
+        ));
+    }
+    text("password", password, MAX_PASSWORD_LEN)
+}
+
+/// Board names are part of the URL, so they are kept to ASCII letters,
+/// digits, `-` and `_`
+pub fn board_name(name: &str) -> Result<(), Invalid> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || name.len() > MAX_BOARD_NAME_LEN || !name.chars().all(valid) {
+        Err(Invalid::new(
+            "bad_board_name",
+            format!(
+                "board names must be 1 to {} letters, digits, '-' or '_'",
+                MAX_BOARD_NAME_LEN
+            ),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn text(what: &str, s: &str, max: usize) -> Result<(), Invalid> {
+    if s.chars().count() > max {
+        Err(Invalid::new(
+            "too_long",
+            format!("{} must be at most {} characters", what, max),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Parses a color given as `#rrggbb`
+pub fn color(s: &str) -> Result<u32, Invalid> {
+    match s.strip_prefix('#') {
+        Some(hex) if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(u32::from_str_radix(hex, 16).unwrap())
+        }
+        _ => Err(Invalid::new("bad_color", "color must be given as #rrggbb")),
+    }
+}
+
+fn points(points: &[Point], max: usize) -> Result<(), Invalid> {
+    if points.len() > max {
+        return Err(Invalid::new(
+            "too_many_points",
+            format!("a line can have at most {} points", max),
+        ));
+    }
+    let in_range = |v: i32| (-MAX_COORD..=MAX_COORD).contains(&v);
+    if points.iter().any(|p| !in_range(p.x) || !in_range(p.y)) {
+        return Err(Invalid::new(
+            "out_of_range",
+            format!("coordinates must be within ±{}", MAX_COORD),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// The code a message is rejected with
+    fn code(msg: &str) -> &'static str {
+        match parse(msg) {
+            Ok(cmd) => panic!("{} was accepted as {:?}", msg, cmd),
+            Err(e) => e.code,
+        }
+    }
+
+    fn rejected(msg: Value) -> &'static str {
+        code(&msg.to_string())
+    }
+
+    fn accepted(msg: Value) -> bool {
+        parse(&msg.to_string()).is_ok()
+    }
+
+    fn long(n: usize) -> String {
+        "a".repeat(n)
+    }
+
+    #[test]
+    fn unknown_and_untyped_messages() {
+        assert_eq!(code(""), "bad_request");
+        assert_eq!(code("not json"), "bad_request");
+        assert_eq!(code(r#"{"type": "list_boards""#), "bad_request");
+        assert_eq!(code("[]"), "bad_request");
+        assert_eq!(code(r#"{"data": []}"#), "bad_request");
+        assert_eq!(code(r#"{"type": 1}"#), "bad_request");
+        assert_eq!(code(r#"{"type": "set_user", "data": "alice"}"#), "bad_request");
+        // Types are snake_case
+        assert_eq!(code(r#"{"type": "Undo"}"#), "bad_request");
+    }
+
+    #[test]
+    fn commands_without_arguments() {
+        for cmd in &["logout", "undo", "redo", "clear", "redraw", "refresh", "list_boards", "who"] {
+            assert!(accepted(json!({ "type": cmd })), "{}", cmd);
+        }
+    }
+
+    #[test]
+    fn hello() {
+        assert_eq!(code(r#"{"type": "hello"}"#), "bad_request");
+        assert_eq!(code(r#"{"type": "hello", "version": "2"}"#), "bad_request");
+        assert_eq!(code(r#"{"type": "hello", "version": -1}"#), "bad_request");
+        assert_eq!(code(r#"{"type": "hello", "version": 2.5}"#), "bad_request");
+        assert_eq!(code(r#"{"type": "hello", "version": 4294967296}"#), "bad_request");
+        assert!(accepted(json!({ "type": "hello", "version": 2 })));
+    }
+
+    #[test]
+    fn login_and_new_user() {
+        for cmd in &["login", "new_user"] {
+            let msg = |username: &str, password: &str| {
+                json!({ "type": cmd, "username": username, "password": password })
+            };
+            assert_eq!(rejected(json!({ "type": cmd, "username": "alice" })), "bad_request");
+            assert_eq!(rejected(json!({ "type": cmd, "password": "secret" })), "bad_request");
+            assert_eq!(rejected(json!({ "type": cmd, "username": 1, "password": "secret" })), "bad_request");
+            assert_eq!(rejected(json!({ "type": cmd, "username": "alice", "password": null })), "bad_request");
+            assert_eq!(rejected(msg("", "secret")), "bad_name");
+            assert_eq!(rejected(msg("   ", "secret")), "bad_name");
+            assert_eq!(rejected(msg("al\nice", "secret")), "bad_name");
+            assert_eq!(rejected(msg(&long(MAX_NAME_LEN + 1), "secret")), "too_long");
+            assert_eq!(rejected(msg("alice", &long(MAX_PASSWORD_LEN + 1))), "too_long");
+            assert!(accepted(msg(&long(MAX_NAME_LEN), &long(MAX_PASSWORD_LEN))));
+            assert!(accepted(msg("alice", &long(MIN_PASSWORD_LEN))));
+        }
+        // Only passwords being set must meet the minimum; older accounts may
+        // still sign in with theirs
+        let new_user = json!({ "type": "new_user", "username": "alice", "password": "" });
+        assert_eq!(rejected(new_user), "bad_password");
+        assert!(accepted(json!({ "type": "login", "username": "alice", "password": "" })));
+    }
+
+    #[test]
+    fn auth() {
+        assert_eq!(rejected(json!({ "type": "auth", "session": 1 })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "auth", "session": "not hex" })), "bad_session");
+        assert_eq!(rejected(json!({ "type": "auth", "session": "00ff" })), "bad_session");
+        assert_eq!(rejected(json!({ "type": "auth", "session": "0f".repeat(17) })), "bad_session");
+        assert!(accepted(json!({ "type": "auth" })));
+        assert!(accepted(json!({ "type": "auth", "session": "0f".repeat(16) })));
+    }
+
+    #[test]
+    fn redeem_and_revoke_invite() {
+        for cmd in &["redeem", "revoke_invite"] {
+            assert_eq!(rejected(json!({ "type": cmd })), "bad_request");
+            assert_eq!(rejected(json!({ "type": cmd, "invite": [] })), "bad_request");
+            assert_eq!(rejected(json!({ "type": cmd, "invite": long(MAX_INVITE_LEN + 1) })), "too_long");
+            assert!(accepted(json!({ "type": cmd, "invite": long(MAX_INVITE_LEN) })));
+        }
+        let redeem = |guest: Value| json!({ "type": "redeem", "invite": "x", "guest": guest });
+        assert_eq!(rejected(redeem(json!(1))), "bad_request");
+        assert_eq!(rejected(redeem(json!(long(MAX_INVITE_LEN + 1)))), "too_long");
+        assert!(accepted(redeem(json!(null))));
+        assert!(accepted(redeem(json!(long(MAX_INVITE_LEN)))));
+    }
+
+    #[test]
+    fn create_invite() {
+        let invite = |board: &str, role: &str, expires_in: Value, max_uses: Value| {
+            json!({
+                "type": "create_invite",
+                "board": board,
+                "role": role,
+                "expires_in": expires_in,
+                "max_uses": max_uses,
+            })
+        };
+        assert_eq!(rejected(json!({ "type": "create_invite", "board": "main", "role": "editor" })), "bad_request");
+        assert_eq!(rejected(invite("main", "admin", json!(60), json!(1))), "bad_request");
+        assert_eq!(rejected(invite("main", "Editor", json!(60), json!(1))), "bad_request");
+        assert_eq!(rejected(invite("main", "editor", json!(-60), json!(1))), "bad_request");
+        assert_eq!(rejected(invite("main", "editor", json!("60"), json!(1))), "bad_request");
+        assert_eq!(rejected(invite("main", "editor", json!(60), json!(1.5))), "bad_request");
+        assert_eq!(rejected(invite("main", "editor", json!(60), json!(4_294_967_296u64))), "bad_request");
+        assert_eq!(rejected(invite("a b", "editor", json!(60), json!(1))), "bad_board_name");
+        assert_eq!(rejected(invite("main", "owner", json!(60), json!(1))), "bad_role");
+        assert_eq!(rejected(invite("main", "editor", json!(0), json!(1))), "bad_expiry");
+        assert_eq!(rejected(invite("main", "editor", json!(MAX_INVITE_TTL + 1), json!(1))), "bad_expiry");
+        assert_eq!(rejected(invite("main", "editor", json!(60), json!(0))), "bad_uses");
+        assert!(accepted(invite("main", "viewer", json!(MAX_INVITE_TTL), json!(1))));
+    }
+
+    #[test]
+    fn change_password() {
+        let msg = |old: &str, new: &str| {
+            json!({ "type": "change_password", "old_password": old, "new_password": new })
+        };
+        assert_eq!(rejected(json!({ "type": "change_password", "old_password": "old" })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "change_password", "new_password": "new" })), "bad_request");
+        assert_eq!(rejected(msg("old", "")), "bad_password");
+        assert!(accepted(msg("old", &long(MIN_PASSWORD_LEN))));
+        assert_eq!(rejected(msg(&long(MAX_PASSWORD_LEN + 1), "new")), "too_long");
+        assert_eq!(rejected(msg("old", &long(MAX_PASSWORD_LEN + 1))), "too_long");
+        assert!(accepted(msg("", "new")));
+    }
+
+    #[test]
+    fn rename_delete_account_and_unlock() {
+        assert_eq!(rejected(json!({ "type": "rename" })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "rename", "name": false })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "rename", "name": "" })), "bad_name");
+        assert_eq!(rejected(json!({ "type": "rename", "name": "bob\u{7}" })), "bad_name");
+        assert_eq!(rejected(json!({ "type": "rename", "name": long(MAX_NAME_LEN + 1) })), "too_long");
+        assert!(accepted(json!({ "type": "rename", "name": "Bob" })));
+
+        assert_eq!(rejected(json!({ "type": "delete_account" })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "delete_account", "password": 1 })), "bad_request");
+        assert_eq!(
+            rejected(json!({ "type": "delete_account", "password": long(MAX_PASSWORD_LEN + 1) })),
+            "too_long"
+        );
+        assert!(accepted(json!({ "type": "delete_account", "password": "secret" })));
+
+        assert_eq!(rejected(json!({ "type": "unlock", "username": null })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "unlock", "username": "\t" })), "bad_name");
+        assert_eq!(rejected(json!({ "type": "unlock", "username": long(MAX_NAME_LEN + 1) })), "too_long");
+        assert!(accepted(json!({ "type": "unlock", "username": "bob" })));
+    }
+
+    #[test]
+    fn set_color() {
+        assert_eq!(rejected(json!({ "type": "set_color" })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "set_color", "data": 255 })), "bad_request");
+        for data in &["", "#", "ff0000", "#ff000", "#ff00000", "#gg0000", "#+f0000", "#ff 000", "#ff00\u{e9}"] {
+            assert_eq!(rejected(json!({ "type": "set_color", "data": data })), "bad_color", "{:?}", data);
+        }
+        assert!(accepted(json!({ "type": "set_color", "data": "#00fF80" })));
+        assert_eq!(color("#00fF80").unwrap(), 0x00ff80);
+    }
+
+    #[test]
+    fn draw() {
+        let draw = |data: Value| json!({ "type": "draw", "data": data });
+        assert_eq!(rejected(json!({ "type": "draw" })), "bad_request");
+        assert_eq!(rejected(draw(json!({ "x": 1, "y": 2 }))), "bad_request");
+        assert_eq!(rejected(draw(json!([[1]]))), "bad_request");
+        assert_eq!(rejected(draw(json!([{ "x": 1 }]))), "bad_request");
+        assert_eq!(rejected(draw(json!([{ "x": "1", "y": 2 }]))), "bad_request");
+        assert_eq!(rejected(draw(json!([{ "x": 1.5, "y": 2 }]))), "bad_request");
+        assert_eq!(rejected(draw(json!([{ "x": 1, "y": 2_147_483_648i64 }]))), "bad_request");
+        assert_eq!(rejected(draw(json!([{ "x": i64::MIN, "y": 2 }]))), "bad_request");
+        assert_eq!(code(r#"{"type": "draw", "data": [{"x": 1, "y": 9223372036854775808}]}"#), "bad_request");
+        assert_eq!(rejected(draw(json!([{ "x": MAX_COORD + 1, "y": 0 }]))), "out_of_range");
+        assert_eq!(rejected(draw(json!([{ "x": 0, "y": -MAX_COORD - 1 }]))), "out_of_range");
+
+        let points = |n: usize| json!(vec![json!({ "x": MAX_COORD, "y": -MAX_COORD }); n]);
+        assert_eq!(rejected(draw(points(MAX_POINTS + 1))), "too_many_points");
+        assert!(accepted(draw(points(MAX_POINTS))));
+    }
+
+    #[test]
+    fn draw_tmp() {
+        let draw_tmp = |color: Value, data: Value| json!({ "type": "draw_tmp", "color": color, "data": data });
+        assert_eq!(rejected(json!({ "type": "draw_tmp", "data": [] })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "draw_tmp", "color": 0 })), "bad_request");
+        assert_eq!(rejected(draw_tmp(json!("#000000"), json!([]))), "bad_request");
+        assert_eq!(rejected(draw_tmp(json!(-1), json!([]))), "bad_request");
+        assert_eq!(rejected(draw_tmp(json!(0x100_0000), json!([]))), "bad_color");
+        assert_eq!(rejected(draw_tmp(json!(0), json!([{ "x": 0.5, "y": 0 }]))), "bad_request");
+        assert_eq!(rejected(draw_tmp(json!(0), json!([{ "x": 0, "y": 4_294_967_296i64 }]))), "bad_request");
+        assert_eq!(rejected(draw_tmp(json!(0), json!([{ "x": 0, "y": MAX_COORD + 1 }]))), "out_of_range");
+
+        let points = |n: usize| json!(vec![json!({ "x": 0, "y": 0 }); n]);
+        assert_eq!(rejected(draw_tmp(json!(0), points(MAX_TMP_POINTS + 1))), "too_many_points");
+        assert!(accepted(draw_tmp(json!(0xff_ffff), points(MAX_TMP_POINTS))));
+    }
+
+    #[test]
+    fn cursor() {
+        assert_eq!(rejected(json!({ "type": "cursor", "x": 1 })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "cursor", "x": 1.25, "y": 1 })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "cursor", "x": 1, "y": 99_999_999_999i64 })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "cursor", "x": -MAX_COORD - 1, "y": 1 })), "out_of_range");
+        assert!(accepted(json!({ "type": "cursor", "x": 1, "y": -1 })));
+    }
+
+    #[test]
+    fn resume_and_chat_history() {
+        assert_eq!(rejected(json!({ "type": "resume" })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "resume", "since_version": -1 })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "resume", "since_version": 1e3 })), "bad_request");
+        assert_eq!(code(r#"{"type": "resume", "since_version": 18446744073709551616}"#), "bad_request");
+        assert!(accepted(json!({ "type": "resume", "since_version": u64::MAX })));
+
+        assert_eq!(rejected(json!({ "type": "chat_history", "before": "1" })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "chat_history", "before": -1 })), "bad_request");
+        assert!(accepted(json!({ "type": "chat_history" })));
+        assert!(accepted(json!({ "type": "chat_history", "before": 10 })));
+    }
+
+    #[test]
+    fn boards() {
+        let too_long = long(MAX_BOARD_NAME_LEN + 1);
+        let bad = ["", "a b", "a/b", "..", "caf\u{e9}", too_long.as_str()];
+        for cmd in &["create_board", "delete_board"] {
+            assert_eq!(rejected(json!({ "type": cmd })), "bad_request");
+            assert_eq!(rejected(json!({ "type": cmd, "name": 1 })), "bad_request");
+            for name in &bad {
+                assert_eq!(rejected(json!({ "type": cmd, "name": name })), "bad_board_name", "{:?}", name);
+            }
+            assert!(accepted(json!({ "type": cmd, "name": "Team_1-a" })));
+        }
+
+        let rename = |name: &str, new_name: &str| {
+            json!({ "type": "rename_board", "name": name, "new_name": new_name })
+        };
+        assert_eq!(rejected(json!({ "type": "rename_board", "name": "a" })), "bad_request");
+        for name in &bad {
+            assert_eq!(rejected(rename(name, "b")), "bad_board_name");
+            assert_eq!(rejected(rename("a", name)), "bad_board_name");
+        }
+        assert!(accepted(rename("a", &long(MAX_BOARD_NAME_LEN))));
+    }
+
+    #[test]
+    fn grant_and_revoke() {
+        let grant = |board: &str, username: &str, role: &str| {
+            json!({ "type": "grant", "board": board, "username": username, "role": role })
+        };
+        assert_eq!(rejected(json!({ "type": "grant", "board": "a", "username": "bob" })), "bad_request");
+        assert_eq!(rejected(grant("a", "bob", "Editor")), "bad_request");
+        assert_eq!(rejected(grant("a", "bob", "admin")), "bad_request");
+        assert_eq!(rejected(grant("a b", "bob", "editor")), "bad_board_name");
+        assert_eq!(rejected(grant("a", "", "editor")), "bad_name");
+        assert_eq!(rejected(grant("a", &long(MAX_NAME_LEN + 1), "editor")), "too_long");
+        assert!(accepted(grant("a", "bob", "owner")));
+
+        let revoke = |board: &str, username: &str| json!({ "type": "revoke", "board": board, "username": username });
+        assert_eq!(rejected(json!({ "type": "revoke", "username": "bob" })), "bad_request");
+        assert_eq!(rejected(revoke("", "bob")), "bad_board_name");
+        assert_eq!(rejected(revoke("a", "\t")), "bad_name");
+        assert!(accepted(revoke("a", "bob")));
+    }
+
+    #[test]
+    fn chat() {
+        assert_eq!(rejected(json!({ "type": "chat" })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "chat", "text": ["hi"] })), "bad_request");
+        assert_eq!(rejected(json!({ "type": "chat", "text": "" })), "empty_message");
+        assert_eq!(rejected(json!({ "type": "chat", "text": " \n " })), "empty_message");
+        assert_eq!(rejected(json!({ "type": "chat", "text": long(MAX_CHAT_LEN + 1) })), "too_long");
+        // Lengths are in characters, not bytes
+        assert!(accepted(json!({ "type": "chat", "text": "\u{e9}".repeat(MAX_CHAT_LEN) })));
+    }
+
+    #[test]
+    fn login_replies() {
+        let reply = |code| Invalid::new(code, "message").login_reply().to_string();
+        assert_eq!(reply("wrong"), r#"{"type":"wrong"}"#);
+        assert_eq!(reply("not_exists"), r#"{"type":"not_exists"}"#);
+        assert_eq!(reply("user_exists"), r#"{"type":"error","code":"user_exists","message":"message"}"#);
+    }
+}